@@ -1,51 +1,57 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex as SyncMutex};
 
-use serenity::{http::Http, model::id::ChannelId};
+use serenity::http::Http;
 use tokio::sync::broadcast;
 
-use crate::actions::{AssistantAction, MusicBotAction};
+use crate::actions::AssistantAction;
+use crate::guild_settings::GuildSettingsStore;
+use crate::music_bot_profile::MusicBotProfiles;
 
 pub async fn action_handler_loop(
     http: Arc<Http>,
-    channel: ChannelId,
+    guild_settings: Arc<SyncMutex<GuildSettingsStore>>,
+    profiles: Arc<MusicBotProfiles>,
     mut action_rx: broadcast::Receiver<AssistantAction>,
 ) {
     loop {
-        let action = action_rx.recv().await.unwrap();
+        let action = match action_rx.recv().await {
+            Ok(action) => action,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                println!("Action handler fell behind, skipped {} actions", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
 
         match action {
-            AssistantAction::MusicBot(music_bot_action) => match music_bot_action {
-                MusicBotAction::Summon => {
-                    channel.say(&http, "=join").await.unwrap();
-                }
-                MusicBotAction::Dismiss => {
-                    channel.say(&http, "=leave").await.unwrap();
-                }
-                MusicBotAction::Request(title) => {
-                    channel.say(&http, format!("=p {}", title)).await.unwrap();
-                }
-                MusicBotAction::Skip => {
-                    channel.say(&http, "=skip").await.unwrap();
-                }
-                MusicBotAction::Shuffle => {
-                    channel.say(&http, "=shuffle").await.unwrap();
-                }
-                MusicBotAction::Loop => {
-                    channel.say(&http, "=loop").await.unwrap();
-                }
-                MusicBotAction::Clear => {
-                    channel.say(&http, "=clear").await.unwrap();
-                }
-                MusicBotAction::BassBoost => {
-                    channel.say(&http, "=bb").await.unwrap();
-                }
-                MusicBotAction::PlayPlaylist(playlist) => {
-                    channel
-                        .say(&http, format!("=playlist play {}", playlist))
-                        .await
-                        .unwrap();
-                }
-            },
+            AssistantAction::MusicBot(guild_id, music_bot_action) => {
+                let settings = guild_settings.lock().unwrap().get(guild_id);
+                let channel = match settings.music_cmd_channel {
+                    Some(channel) => channel,
+                    None => {
+                        println!("No music command channel set for guild {}, dropped {:?}", guild_id, music_bot_action);
+                        continue;
+                    }
+                };
+                let profile = match profiles.get_or_default(settings.music_bot_profile.as_deref()) {
+                    Some(profile) => profile,
+                    None => {
+                        println!("Unknown music bot profile for guild {}, dropped {:?}", guild_id, music_bot_action);
+                        continue;
+                    }
+                };
+
+                match profile.render(&music_bot_action) {
+                    Some(command) => {
+                        if let Err(e) = channel.say(&http, command).await {
+                            println!("Couldn't send {:?} to {}: {}", music_bot_action, channel, e);
+                        }
+                    }
+                    None => {
+                        println!("Music bot profile has no command for {:?}", music_bot_action);
+                    }
+                }
+            }
         }
     }
 }
@@ -1,7 +1,11 @@
+use serenity::model::id::GuildId;
+
+// Actions are tagged with the guild whose assistant asked for them, so each
+// guild's handler only acts on its own.
 #[derive(Clone)]
 #[derive(Debug)]
 pub enum AssistantAction {
-    MusicBot(MusicBotAction)
+    MusicBot(GuildId, MusicBotAction)
 }
 
 #[derive(Clone)]
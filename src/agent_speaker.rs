@@ -1,22 +1,23 @@
 use std::sync::{Arc, Mutex as SyncMutex};
 
-use async_openai::config::OpenAIConfig;
-use async_openai::types::{CreateSpeechRequestArgs, SpeechModel, Voice};
-use async_openai::Client as OpenAIClient;
 use songbird::id::GuildId;
-use songbird::input::File;
-use songbird::tracks::{LoopState, PlayMode, Track, TrackHandle};
+use songbird::input::Input;
 use songbird::Songbird;
-use tokio::sync::Mutex;
-use uuid::Uuid;
+use tokio::sync::{mpsc, oneshot};
 
+use crate::mixer::DuckingMixer;
+use crate::output_scheduler::{OutputPriority, OutputScheduler, PlaybackOutcome};
 use crate::sound_store::SoundStore;
+use crate::text_to_speech::{SynthesizedSpeech, TextToSpeech};
 
+// Cheap to clone, clones share the same output queue.
+#[derive(Clone)]
 pub struct AgentSpeaker {
-    songbird: Arc<Songbird>,
-    guild_id: GuildId,
-    oai_client: Arc<OpenAIClient<OpenAIConfig>>,
-    track_handle: Arc<Mutex<Option<TrackHandle>>>,
+    tts: Arc<dyn TextToSpeech>,
+    // Everything the speaker plays goes through here, so the ping, earcons
+    // and speech take turns instead of cutting each other off. Other tracks
+    // in the call are left alone.
+    output: OutputScheduler,
     sound_store: Arc<SyncMutex<SoundStore>>,
 }
 
@@ -24,82 +25,101 @@ impl AgentSpeaker {
     pub fn new(
         songbird: Arc<Songbird>,
         guild_id: GuildId,
+        tts: Arc<dyn TextToSpeech>,
         sound_store: Arc<SyncMutex<SoundStore>>,
+        mixer: DuckingMixer,
     ) -> Self {
         AgentSpeaker {
-            songbird: songbird,
-            guild_id: guild_id,
-            oai_client: Arc::new(OpenAIClient::new()),
-            track_handle: Arc::new(Mutex::new(None)),
+            tts: tts,
+            output: OutputScheduler::new(songbird, guild_id, mixer),
             sound_store: sound_store,
         }
     }
 
-    pub async fn speak(&mut self, text: &str) {
-        let voice_request: async_openai::types::CreateSpeechRequest =
-            CreateSpeechRequestArgs::default()
-                .input(text)
-                .voice(Voice::Onyx)
-                .model(SpeechModel::Tts1)
-                .build()
-                .unwrap();
-
-        let speaker_handle_lock = self.track_handle.clone();
-        let songbird_lock = self.songbird.get(self.guild_id.clone()).unwrap();
-        let oai_client = self.oai_client.clone();
-        match oai_client.audio().speech(voice_request).await {
+    // Decoded straight from memory, the container is recognised from the data itself.
+    fn speech_input(speech: SynthesizedSpeech) -> Input {
+        Input::from(speech.audio)
+    }
+
+    fn sound_input(&self, name: &str) -> Option<Input> {
+        match self.sound_store.lock() {
+            Ok(sound_store) => sound_store.get(name).map(|memory| memory.new_handle().into()),
+            Err(_) => None,
+        }
+    }
+
+    // Looks in the sound store before going to the backend, and keeps what
+    // the backend comes back with for next time.
+    async fn synthesize(&self, text: &str) -> Option<Input> {
+        let identity = self.tts.identity();
+        if let Some(audio) = self.sound_store.lock().unwrap().get_speech(&identity, text) {
+            return Some(Input::from(audio));
+        }
+
+        match self.tts.synthesize(text).await {
             Ok(speech) => {
-                let path = format!("../../tmp/{}.mp3", Uuid::new_v4());
-                speech.save(path.clone()).await.unwrap();
-
-                let mut songbird_guard = songbird_lock.lock().await;
-                let source = File::new(path);
-                let new_track_handle = songbird_guard.play_only_input(source.into());
-                let mut handle_guard = speaker_handle_lock.lock().await;
-                *handle_guard = Some(new_track_handle);
+                self.sound_store
+                    .lock()
+                    .unwrap()
+                    .insert_speech(&identity, text, speech.audio.clone(), false);
+                Some(Self::speech_input(speech))
             }
             Err(e) => {
                 println!("{}", e.to_string());
+                None
             }
         }
     }
 
-    pub async fn acknowledge(&self) {
-        let songbird_lock = self.songbird.get(self.guild_id.clone()).unwrap();
-        let mut songbird_guard = songbird_lock.lock().await;
+    async fn say(&self, text: &str, priority: OutputPriority) -> Option<oneshot::Receiver<PlaybackOutcome>> {
+        let input = self.synthesize(text).await?;
+        Some(self.output.enqueue(input, priority, false).await)
+    }
 
-        if let Ok(song_store) = self.sound_store.lock() {
-            if let Some(memory) = song_store.get("acknowledge") {
-                songbird_guard.play_only(memory.new_handle().try_into().unwrap());
-            }
-        }
+    // Queues a reply, the receiver reports once it has been played.
+    pub async fn speak(&self, text: &str) -> Option<oneshot::Receiver<PlaybackOutcome>> {
+        self.say(text, OutputPriority::Response).await
     }
 
-    pub async fn start_ping(&self) {
-        let songbird_lock = self.songbird.get(self.guild_id.clone()).unwrap();
-        let mut songbird_guard = songbird_lock.lock().await;
+    // Like `speak`, for anything that isn't a reply, so it waits for the
+    // assistant to finish talking.
+    pub async fn announce(&self, text: &str) -> Option<oneshot::Receiver<PlaybackOutcome>> {
+        self.say(text, OutputPriority::Announcement).await
+    }
 
-        if let Ok(song_store) = self.sound_store.lock() {
-            if let Some(memory) = song_store.get("ping") {
-                let track = Track::new(memory.new_handle().into()).loops(LoopState::Infinite);
-                songbird_guard.play_only(track);
-            }
+    // Speaks sentences as they come in, each one is synthesized and queued
+    // up behind the last while the rest of the reply is still on its way.
+    pub async fn speak_stream(&self, mut sentences: mpsc::UnboundedReceiver<String>) {
+        while let Some(sentence) = sentences.recv().await {
+            // Queuing it cuts off the waiting ping.
+            self.speak(&sentence).await;
         }
     }
 
-    pub async fn is_finished(&self) -> bool {
-        let track_guard = self.track_handle.lock().await;
-        if let Some(handle) = track_guard.as_ref() {
-            if let Ok(info) = handle.get_info().await {
-                return info.playing == PlayMode::End;
-            }
+    pub async fn acknowledge(&self) {
+        if let Some(input) = self.sound_input("acknowledge") {
+            self.output.enqueue(input, OutputPriority::Earcon, false).await;
+        }
+    }
+
+    // Loops until anything else is queued or the reply is over.
+    pub async fn start_ping(&self) {
+        if let Some(input) = self.sound_input("ping") {
+            self.output.enqueue(input, OutputPriority::Earcon, true).await;
         }
-        return true;
+    }
+
+    // Stops the ping, anything still queued is played out.
+    pub fn stop_waiting(&self) {
+        self.output.cancel_looping();
+    }
+
+    // Whether everything queued has been said.
+    pub fn is_finished(&self) -> bool {
+        self.output.is_idle()
     }
 
     pub async fn stop(&self) {
-        let songbird_lock = self.songbird.get(self.guild_id.clone()).unwrap();
-        let mut songbird_guard = songbird_lock.lock().await;
-        songbird_guard.stop();
+        self.output.cancel_all();
     }
 }
@@ -1,71 +1,103 @@
-use std::{env, sync::{Arc, atomic::{AtomicBool, Ordering}}};
-use async_openai::{Client, types::{CreateTranscriptionRequestArgs,
-                                    AudioInput,
-                                    ChatCompletionRequestSystemMessageArgs,
-                                    CreateChatCompletionRequestArgs,
-                                    ChatCompletionRequestMessage,
-                                    ChatCompletionRequestUserMessageArgs,
-                                    ChatCompletionRequestAssistantMessageArgs, ChatCompletionFunctions, FinishReason, ChatChoice, FunctionCall}, config::OpenAIConfig};
-use serde_json::Value;
-use tokio::sync::broadcast;
-
-use crate::{agent_speaker::AgentSpeaker, actions::{AssistantAction, MusicBotAction}};
+use std::{env, sync::{Arc, atomic::{AtomicBool, Ordering}}, time::{Duration, Instant}};
+use async_openai::types::{ChatCompletionRequestSystemMessageArgs,
+                          ChatCompletionRequestMessage,
+                          ChatCompletionRequestUserMessageArgs,
+                          ChatCompletionRequestAssistantMessageArgs,
+                          ChatCompletionRequestToolMessageArgs,
+                          ChatCompletionMessageToolCall, ChatCompletionTool};
+use bytes::Bytes;
+use serenity::model::id::GuildId;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{agent_speaker::AgentSpeaker, actions::AssistantAction, llm::{LlmBackend, LlmReply, LlmResult}, sentence_splitter::SentenceSplitter, speech_to_text::SpeechToText, tools::{self, ToolContext, ToolRegistry}};
+
+// Bounds how many times the model may chain tool calls within a single reply.
+const MAX_TOOL_ROUNDS: usize = 4;
+
+pub enum Utterance {
+    // WAV encoded audio that still needs to go through speech to text.
+    Audio(Bytes),
+    // Text already transcribed on-device while the user was speaking.
+    Transcript(String),
+}
 
 pub struct DiscordAssistant {
-    oai_client: Arc<Client<OpenAIConfig>>,
+    llm: Arc<dyn LlmBackend>,
+    stt: Arc<dyn SpeechToText>,
     pub speaker: AgentSpeaker,
     respondant: Option<u32>,
     is_responding: AtomicBool,
     messages: Vec<ChatCompletionRequestMessage>,
-    functions: Vec<ChatCompletionFunctions>,
-    assistant_model: String,
+    tools: ToolRegistry,
+    tool_schemas: Vec<ChatCompletionTool>,
+    tool_context: ToolContext,
     assistant_pragma: String,
-    action_channel: broadcast::Sender<AssistantAction>
+    history_timeout: Duration,
+    // How many user turns are kept in the history, older ones are dropped.
+    history_turns: usize,
+    last_activity: Option<Instant>
 }
 
 impl DiscordAssistant {
-    pub async fn new(oai_client: Arc<Client<OpenAIConfig>>, speaker: AgentSpeaker, action_channel: broadcast::Sender<AssistantAction>) -> DiscordAssistant {    
+    pub async fn new(llm: Arc<dyn LlmBackend>, stt: Arc<dyn SpeechToText>, speaker: AgentSpeaker, guild_id: GuildId, action_channel: broadcast::Sender<AssistantAction>) -> DiscordAssistant {    
         let assistant_instructions = env::var("ASSISTANT_INSTRUCTIONS").unwrap();
-        let assistant_model = env::var("ASSISTANT_MODEL").unwrap();
+        let tools = tools::default_tools();
+        let tool_schemas = tools.schemas();
+        let history_timeout_secs: u64 = env::var("ASSISTANT_HISTORY_TIMEOUT_SECS")
+            .unwrap_or("300".into())
+            .parse()
+            .expect("Couldn't parse env ASSISTANT_HISTORY_TIMEOUT_SECS!");
+        let history_turns: usize = env::var("ASSISTANT_HISTORY_TURNS")
+            .unwrap_or("10".into())
+            .parse()
+            .expect("Couldn't parse env ASSISTANT_HISTORY_TURNS!");
 
         DiscordAssistant {
-            oai_client: oai_client,
+            llm: llm,
+            stt: stt,
             speaker: speaker,
             respondant: None,
             is_responding: AtomicBool::new(false),
             messages: Vec::default(),
-            functions: Vec::default(),
-            assistant_model: assistant_model,
+            tools: tools,
+            tool_schemas: tool_schemas,
+            tool_context: ToolContext { guild_id: guild_id, action_channel: action_channel },
             assistant_pragma: assistant_instructions,
-            action_channel: action_channel
+            history_timeout: Duration::from_secs(history_timeout_secs),
+            history_turns: history_turns.max(1),
+            last_activity: None
         }
     }
 
-    pub async fn send_message(&mut self, audio_input: AudioInput) {
+    pub async fn send_message(&mut self, utterance: Utterance) {
         self.is_responding.store(true, Ordering::SeqCst);
 
-        let transcription_text = self.speech_to_text(audio_input).await;
+        let transcription_text = match utterance {
+            Utterance::Audio(wav) => self.speech_to_text(wav).await,
+            Utterance::Transcript(text) => {
+                println!("stt: {}", text);
+                text
+            }
+        };
         if !transcription_text.is_empty() {
-            match self.get_response_choice(&transcription_text).await {
-                Some(choice) => {
-                    if let Some(finish_reason) = choice.finish_reason {
-                        if FinishReason::FunctionCall == finish_reason {
-                            self.handle_function_call(&choice.message.function_call.unwrap()).await;
-                        } else {
-                            let content = choice.message.content.clone().unwrap();
-                            self.messages.push(ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessageArgs::default()
-                                .content(content.clone())
-                                .build().unwrap()));
-                            self.speaker.speak(&content).await;
-                        }
-                    };
+            match self.get_response(&transcription_text).await {
+                Some((LlmReply::ToolCalls(tool_calls), spoken)) => {
+                    self.handle_tool_calls(tool_calls, spoken).await;
+                },
+                Some((LlmReply::Message(content), _)) => {
+                    // Already spoken while it streamed in.
+                    self.record_reply(&content);
                 },
                 None => {
-                    self.speaker.speak("Sorry, I'm a big dum guy and couldn't think of a response, tee hee!").await;
+                    let apology = "Sorry, I'm a big dum guy and couldn't think of a response, tee hee!";
+                    self.record_reply(apology);
+                    self.speaker.speak(apology).await;
                 }
             }
         }
-        
+
+        // Nothing more is coming, don't leave the ping going.
+        self.speaker.stop_waiting();
         self.is_responding.store(false, Ordering::SeqCst);
     }
 
@@ -91,166 +123,195 @@ impl DiscordAssistant {
         return self.respondant;
     }
 
-    async fn get_response_choice(&mut self, message_text: &str) -> Option<ChatChoice> {
+    // The reply, along with what was said of it while it streamed in.
+    async fn get_response(&mut self, message_text: &str) -> Option<(LlmReply, String)> {
         self.messages.push(ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessageArgs::default()
             .content(message_text)
             .build().unwrap()));
+        self.trim_history();
 
-        let request = CreateChatCompletionRequestArgs::default()
-            .model(self.assistant_model.clone())
-            .messages(self.messages.clone())
-            .functions(self.functions.clone())
-            .build().unwrap();
-
-        let response = self.oai_client.chat().create(request).await;
-
-        match response {
-            Ok(response) => {
-                if let Some(choice) = response.choices.first() {
-                    return Some(choice.clone());
-                } else {
-                    None
-                }
-            },
-            Err(_) => {
+        match self.stream_reply().await {
+            (Ok(reply), spoken) => Some((reply, spoken)),
+            (Err(e), _) => {
+                println!("LLM error: {}", e);
                 None
             }
         }
     }
-    
-    async fn handle_function_call(&mut self, function_call: &FunctionCall) {
-        match function_call.name.as_str() {
-            "done" => {
-                self.respondant = None;
-                self.speaker.stop().await;
-            },
-            "summon_music_bot" => {
-                self.action_channel.send(AssistantAction::MusicBot(MusicBotAction::Summon)).unwrap();
-                self.speaker.speak(&function_call.arguments).await;
-                self.respondant = None;
-            },
-            "dismiss_music_bot" => {
-                self.action_channel.send(AssistantAction::MusicBot(MusicBotAction::Dismiss)).unwrap();
-                self.speaker.speak(&function_call.arguments).await;
-                self.respondant = None;
-            }
-            "request_music_bot" => {
-                if let Ok(args) = serde_json::from_str::<Value>(&function_call.arguments) {
-                    if let Some(title_value) = args.get("title") {
-                        if let Some(title) = title_value.as_str() {
-                            self.action_channel.send(AssistantAction::MusicBot(MusicBotAction::Request(title.into()))).unwrap();
-                            self.speaker.speak("On it!").await;
-                            self.respondant = None;
-                            return;
-                        }
-                    }
-                    self.speaker.speak("Sorry I made a fucky wucky!").await;
-                    self.respondant = None;
-                }    
-            }       
-            "skip_music_bot" => {
-                self.action_channel.send(AssistantAction::MusicBot(MusicBotAction::Skip)).unwrap();
-                self.speaker.speak(&function_call.arguments).await;
-                self.respondant = None;
+
+    // Asks for the next reply, speaking it sentence by sentence as it is
+    // generated rather than once it is complete. Also returns everything
+    // that was streamed, which the model may send ahead of tool calls.
+    async fn stream_reply(&self) -> (LlmResult, String) {
+        let (text_tx, mut text_rx) = mpsc::unbounded_channel::<String>();
+        let (sentence_tx, sentence_rx) = mpsc::unbounded_channel::<String>();
+
+        let split = async move {
+            let mut splitter = SentenceSplitter::default();
+            let mut spoken = String::new();
+            while let Some(text) = text_rx.recv().await {
+                spoken.push_str(&text);
+                for sentence in splitter.push(&text) {
+                    let _ = sentence_tx.send(sentence);
+                }
             }
-            "shuffle_music_bot" => {
-                self.action_channel.send(AssistantAction::MusicBot(MusicBotAction::Shuffle)).unwrap();
-                self.speaker.speak(&function_call.arguments).await;
-                self.respondant = None;
+            if let Some(rest) = splitter.finish() {
+                let _ = sentence_tx.send(rest);
             }
-            "clear_music_bot" => {
-                self.action_channel.send(AssistantAction::MusicBot(MusicBotAction::Clear)).unwrap();
-                self.speaker.speak(&function_call.arguments).await;
-                self.respondant = None;
+            spoken
+        };
+
+        let (reply, spoken, _) = tokio::join!(
+            self.llm.chat_stream(&self.messages, &self.tool_schemas, text_tx),
+            split,
+            self.speaker.speak_stream(sentence_rx)
+        );
+        (reply, spoken)
+    }
+    
+    async fn handle_tool_calls(&mut self, mut tool_calls: Vec<ChatCompletionMessageToolCall>, mut spoken: String) {
+        // Every call and its result goes into the history, so follow ups like
+        // "add the next song too" know what was already done.
+        let mut fallback_replies: Vec<String> = Vec::new();
+        let mut end_conversation = false;
+
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let mut message = ChatCompletionRequestAssistantMessageArgs::default();
+            message.tool_calls(tool_calls.clone());
+            // The user already heard it, the model should know it said it.
+            if !spoken.is_empty() {
+                message.content(std::mem::take(&mut spoken));
             }
-            "loop_music_bot" => {
-                self.action_channel.send(AssistantAction::MusicBot(MusicBotAction::Loop)).unwrap();
-                self.speaker.speak(&function_call.arguments).await;
-                self.respondant = None;
+            self.messages.push(ChatCompletionRequestMessage::Assistant(message.build().unwrap()));
+
+            for tool_call in &tool_calls {
+                let outcome = self.tools.dispatch(&tool_call.function, &self.tool_context).await;
+                println!("{}: {}", tool_call.function.name, outcome.result);
+
+                self.messages.push(ChatCompletionRequestMessage::Tool(ChatCompletionRequestToolMessageArgs::default()
+                    .tool_call_id(tool_call.id.clone())
+                    .content(outcome.result)
+                    .build().unwrap()));
+                if let Some(reply) = outcome.reply {
+                    fallback_replies.push(reply);
+                }
+                end_conversation |= outcome.end_conversation;
             }
-            "bassboost_music_bot" => {
-                self.action_channel.send(AssistantAction::MusicBot(MusicBotAction::BassBoost)).unwrap();
-                self.speaker.speak(&function_call.arguments).await;
-                self.respondant = None;
+
+            // Nothing to confirm, e.g. the user is done talking.
+            if fallback_replies.is_empty() {
+                break;
             }
-            "play_playlist_music_bot" => {
-                if let Ok(args) = serde_json::from_str::<Value>(&function_call.arguments) {
-                    if let Some(playlist_value) = args.get("playlist") {
-                        if let Some(playlist) = playlist_value.as_str() {
-                            self.action_channel.send(AssistantAction::MusicBot(MusicBotAction::PlayPlaylist(playlist.into()))).unwrap();
-                            self.speaker.speak("On it!").await;
-                            self.respondant = None;
-                            return;
-                        }
+
+            let (reply, next_spoken) = self.stream_reply().await;
+            match reply {
+                Ok(LlmReply::Message(content)) => {
+                    self.record_reply(&content);
+                    if end_conversation {
+                        self.respondant = None;
                     }
+                    return;
+                },
+                Ok(LlmReply::ToolCalls(next_tool_calls)) => {
+                    tool_calls = next_tool_calls;
+                    spoken = next_spoken;
+                },
+                Err(e) => {
+                    println!("LLM error: {}", e);
+                    break;
                 }
-                self.speaker.speak("Sorry I made a fucky wucky!").await;
-                self.respondant = None;
             }
-            _ => {
-                println!("unsupported function call: {}", function_call.name);
+        }
+
+        let fallback_reply = if fallback_replies.iter().any(|reply| reply == tools::ERROR_REPLY) {
+            Some(tools::ERROR_REPLY.to_string())
+        } else {
+            fallback_replies.into_iter().next()
+        };
+        match fallback_reply {
+            Some(reply) => {
+                self.record_reply(&reply);
+                self.speaker.speak(&reply).await;
+            },
+            None => self.speaker.stop_waiting()
+        }
+
+        if end_conversation {
+            self.respondant = None;
+        }
+    }
+
+    // The user talked over the reply. Whatever was still to be said is cut
+    // off, and a tool round left half done is dropped from the history since
+    // the model rejects tool calls without results.
+    pub async fn interrupt(&mut self) {
+        self.speaker.stop().await;
+        self.drop_unanswered_tool_calls();
+        self.is_responding.store(false, Ordering::SeqCst);
+        self.last_activity = Some(Instant::now());
+    }
+
+    fn drop_unanswered_tool_calls(&mut self) {
+        let last_tool_calls = self.messages.iter().rposition(|message| match message {
+            ChatCompletionRequestMessage::Assistant(assistant) => assistant.tool_calls.is_some(),
+            _ => false
+        });
+        if let Some(position) = last_tool_calls {
+            let called = match &self.messages[position] {
+                ChatCompletionRequestMessage::Assistant(assistant) => assistant.tool_calls.as_ref().map_or(0, Vec::len),
+                _ => 0
+            };
+            let answered = self.messages[position + 1..]
+                .iter()
+                .filter(|message| matches!(message, ChatCompletionRequestMessage::Tool(_)))
+                .count();
+            if answered < called {
+                self.messages.truncate(position);
             }
         }
     }
 
+    fn record_reply(&mut self, content: &str) {
+        self.messages.push(ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessageArgs::default()
+            .content(content)
+            .build().unwrap()));
+        self.last_activity = Some(Instant::now());
+    }
+
+    // Keeps the system message and the last few turns so a busy channel
+    // doesn't outgrow the model's context. Turns start at user messages, so
+    // tool calls are never separated from their results.
+    fn trim_history(&mut self) {
+        let turn_starts: Vec<usize> = self.messages
+            .iter()
+            .enumerate()
+            .filter(|(_, message)| matches!(message, ChatCompletionRequestMessage::User(_)))
+            .map(|(position, _)| position)
+            .collect();
+        if turn_starts.len() > self.history_turns {
+            let keep_from = turn_starts[turn_starts.len() - self.history_turns];
+            self.messages.drain(1..keep_from);
+        }
+    }
+
+    // Starts a conversation after the wake word, picking up where the last one
+    // left off unless it went quiet for longer than the history timeout.
+    pub async fn begin_conversation(&mut self) {
+        let expired = match self.last_activity {
+            Some(last_activity) => last_activity.elapsed() >= self.history_timeout,
+            None => true
+        };
+        if expired || self.messages.is_empty() {
+            self.flush().await;
+        }
+    }
+
     pub async fn flush(&mut self) {
         self.messages.clear();
         self.messages.push(ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessageArgs::default()
             .content(self.assistant_pragma.clone())
             .build().unwrap())
         );
-        
-        self.functions.clear();
-        self.functions.push(ChatCompletionFunctions {
-            name: "done".to_string(),
-            description: Some("The user no longer wants to speak or already had their question answered.".to_string()),
-            parameters: serde_json::from_str("{\"type\": \"object\", \"properties\": {}}").unwrap()
-        });
-        self.functions.push(ChatCompletionFunctions {
-            name: "summon_music_bot".to_string(),
-            description: Some("The user wants to add the music bot to the discord channel.".to_string()),
-            parameters: serde_json::from_str("{\"type\": \"object\", \"properties\": {}}").unwrap()
-        });
-        self.functions.push(ChatCompletionFunctions {
-            name: "dismiss_music_bot".to_string(),
-            description: Some("The user wants to remove the music bot from the discord channel.".to_string()),
-            parameters: serde_json::from_str("{\"type\": \"object\", \"properties\": {}}").unwrap()
-        });
-        self.functions.push(ChatCompletionFunctions {
-            name: "request_music_bot".to_string(),
-            description: Some("The user wants to add a song or video by title to the music bot queue.".to_string()),
-            parameters: serde_json::from_str("{\"type\": \"object\", \"properties\": { \"title\": { \"type\": \"string\", \"description\": \"The title of the song or video, e.g. Abba - Dancing Queen\" } }}").unwrap()
-        });
-        self.functions.push(ChatCompletionFunctions {
-            name: "skip_music_bot".to_string(),
-            description: Some("The user wants the music bot to skip a song.".to_string()),
-            parameters: serde_json::from_str("{\"type\": \"object\", \"properties\": {}}").unwrap()
-        });
-        self.functions.push(ChatCompletionFunctions {
-            name: "shuffle_music_bot".to_string(),
-            description: Some("The user wants the music bot to shuffle it's queue.".to_string()),
-            parameters: serde_json::from_str("{\"type\": \"object\", \"properties\": {}}").unwrap()
-        });
-        self.functions.push(ChatCompletionFunctions {
-            name: "clear_music_bot".to_string(),
-            description: Some("The user wants to clear the music bot queue.".to_string()),
-            parameters: serde_json::from_str("{\"type\": \"object\", \"properties\": {}}").unwrap()
-        });
-        self.functions.push(ChatCompletionFunctions {
-            name: "loop_music_bot".to_string(),
-            description: Some("The user wants to loop the last song in the music bot queue.".to_string()),
-            parameters: serde_json::from_str("{\"type\": \"object\", \"properties\": {}}").unwrap()
-        });
-        self.functions.push(ChatCompletionFunctions {
-            name: "bassboost_music_bot".to_string(),
-            description: Some("The user wants to toggle bass boost for the music bot.".to_string()),
-            parameters: serde_json::from_str("{\"type\": \"object\", \"properties\": {}}").unwrap()
-        });
-        self.functions.push(ChatCompletionFunctions {
-            name: "play_playlist_music_bot".to_string(),
-            description: Some("The user wants the music bot to play a specific playlist.".to_string()),
-            parameters: serde_json::from_str("{\"type\": \"object\", \"properties\": { \"playlist\": { \"type\": \"string\", \"description\": \"The title of the playlist.\" } }}").unwrap()
-        });
     }
 
     pub fn set_responding(&self) {
@@ -258,17 +319,19 @@ impl DiscordAssistant {
     }
 
     pub async fn is_responding(&self) -> bool {
-        return self.is_responding.load(Ordering::SeqCst) || !self.speaker.is_finished().await;
+        return self.is_responding.load(Ordering::SeqCst) || !self.speaker.is_finished();
     }
 
-    pub async fn speech_to_text(&self, audio_input: AudioInput) -> String {
-        let request = CreateTranscriptionRequestArgs::default()
-            .file(audio_input)
-            .model("whisper-1")
-            .build().unwrap();
-
-        let response = self.oai_client.audio().transcribe(request).await.unwrap();
-        println!("stt: {}", response.text);
-        return response.text;
+    pub async fn speech_to_text(&self, wav: Bytes) -> String {
+        match self.stt.transcribe(wav).await {
+            Ok(text) => {
+                println!("stt: {}", text);
+                return text;
+            },
+            Err(e) => {
+                println!("Speech to text error: {}", e);
+                return String::new();
+            }
+        }
     }
 }
\ No newline at end of file
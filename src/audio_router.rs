@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use dashmap::DashMap;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::resampler::ListenerEvent;
+
+// How often, in dropped packets, an overflowing listener is reported.
+const DROP_REPORT_INTERVAL: u64 = 50;
+
+struct Route {
+    tx: mpsc::Sender<ListenerEvent>,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+}
+
+pub enum RouteResult {
+    Delivered,
+    // The listener is behind and its queue is full, the packet is lost.
+    Dropped,
+    // The listener is gone without being removed, it likely crashed.
+    Closed,
+    // Nobody is listening to this SSRC.
+    Unrouted,
+}
+
+pub struct RouteStats {
+    pub ssrc: u32,
+    pub delivered: u64,
+    pub dropped: u64,
+}
+
+// Hands audio and silence from the voice driver to listeners without ever
+// waiting on them, a slow listener loses its own packets instead of holding
+// up everyone else's.
+#[derive(Default)]
+pub struct AudioRouter {
+    routes: DashMap<u32, Route>,
+}
+
+impl AudioRouter {
+    pub fn insert(&self, ssrc: u32, tx: mpsc::Sender<ListenerEvent>) {
+        self.routes.insert(
+            ssrc,
+            Route {
+                tx: tx,
+                delivered: AtomicU64::new(0),
+                dropped: AtomicU64::new(0),
+            },
+        );
+    }
+
+    pub fn remove(&self, ssrc: u32) {
+        self.routes.remove(&ssrc);
+    }
+
+    pub fn route(&self, ssrc: u32, event: ListenerEvent) -> RouteResult {
+        let route = match self.routes.get(&ssrc) {
+            Some(route) => route,
+            None => return RouteResult::Unrouted,
+        };
+
+        match route.tx.try_send(event) {
+            Ok(_) => {
+                route.delivered.fetch_add(1, Ordering::Relaxed);
+                RouteResult::Delivered
+            }
+            Err(TrySendError::Full(_)) => {
+                let dropped = route.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped % DROP_REPORT_INTERVAL == 1 {
+                    println!("Listener for SSRC {} is falling behind, {} packets dropped", ssrc, dropped);
+                }
+                RouteResult::Dropped
+            }
+            Err(TrySendError::Closed(_)) => RouteResult::Closed,
+        }
+    }
+
+    pub fn stats(&self) -> Vec<RouteStats> {
+        let mut stats: Vec<RouteStats> = self
+            .routes
+            .iter()
+            .map(|route| RouteStats {
+                ssrc: *route.key(),
+                delivered: route.delivered.load(Ordering::Relaxed),
+                dropped: route.dropped.load(Ordering::Relaxed),
+            })
+            .collect();
+        stats.sort_by_key(|stats| stats.ssrc);
+        stats
+    }
+}
@@ -1,7 +1,5 @@
-use async_openai::config::OpenAIConfig;
-use async_openai::Client as OpenAIClient;
 use async_trait::async_trait;
-use serenity::all::GuildChannel;
+use serenity::all::{Channel, GuildChannel};
 use serenity::cache::GuildRef;
 use serenity::client::Context;
 use serenity::framework::standard::Configuration;
@@ -27,7 +25,6 @@ use serenity::{
 };
 use simple_error::bail;
 use songbird::driver::DecodeMode;
-use songbird::model::id::UserId;
 use songbird::packet::Packet;
 use songbird::{
     model::payload::{ClientDisconnect, Speaking},
@@ -39,29 +36,81 @@ use std::{
     env,
     sync::{Arc, Mutex as SyncMutex},
 };
-use tokio::sync::{broadcast, mpsc, Mutex, RwLock};
+use tokio::sync::{broadcast, Mutex, RwLock};
+use tokio::task::JoinHandle;
 
 use crate::action_handler::action_handler_loop;
 use crate::actions::AssistantAction;
 use crate::agent_speaker::AgentSpeaker;
 use crate::assistant::DiscordAssistant;
+use crate::dsp_pool::DspPool;
+use crate::audio_router::{AudioRouter, RouteResult};
+use crate::llm::LlmBackend;
+use crate::mixer::{DuckSettings, DuckingMixer};
+use crate::music::{self, MusicPlayer};
+use crate::guild_settings::GuildSettingsStore;
+use crate::music_bot_profile::MusicBotProfiles;
 use crate::sound_store::SoundStore;
-use crate::{listener, resampler};
+use crate::speech_to_text::SpeechToText;
+use crate::text_to_speech::TextToSpeech;
+use crate::listener_manager::ListenerManager;
+use crate::resampler::{self, PacketTiming};
 
 pub struct SharedState {
-    pub users: HashMap<u32, mpsc::Sender<resampler::ListenerEvent>>,
-    pub id_to_ssrc: HashMap<UserId, u32>,
-    pub oai_client: Arc<OpenAIClient<OpenAIConfig>>,
+    pub guilds: HashMap<GuildId, GuildState>,
+    pub llm: Arc<dyn LlmBackend>,
+    pub speech_to_text: Arc<dyn SpeechToText>,
+    pub text_to_speech: Arc<dyn TextToSpeech>,
     pub sound_store: Arc<SyncMutex<SoundStore>>,
     pub action_channel_tx: broadcast::Sender<AssistantAction>,
+    pub guild_settings: Arc<SyncMutex<GuildSettingsStore>>,
+    pub music_bot_profiles: Arc<MusicBotProfiles>,
+    pub dsp_pool: Arc<DspPool>,
+    pub duck_settings: DuckSettings,
 }
 
 impl TypeMapKey for SharedState {
     type Value = SharedState;
 }
 
+// Everything belonging to one guild's voice connection. SSRCs are only unique
+// within a connection, so listeners can't be shared between guilds.
+pub struct GuildState {
+    pub assistant: Arc<Mutex<DiscordAssistant>>,
+    // Shares the assistant's output queue, without having to wait on the
+    // assistant to use it.
+    pub speaker: AgentSpeaker,
+    pub listeners: ListenerManager,
+    pub music_player: Option<JoinHandle<()>>,
+}
+
+impl GuildState {
+    pub fn new(
+        assistant: Arc<Mutex<DiscordAssistant>>,
+        speaker: AgentSpeaker,
+        speech_to_text: Arc<dyn SpeechToText>,
+        dsp_pool: Arc<DspPool>,
+    ) -> Self {
+        GuildState {
+            assistant: assistant.clone(),
+            speaker: speaker,
+            listeners: ListenerManager::new(assistant, speech_to_text, dsp_pool),
+            music_player: None,
+        }
+    }
+
+    // Ends the guild's listeners and music player and silences the assistant.
+    pub async fn shutdown(mut self) {
+        self.listeners.shutdown();
+        if let Some(music_player) = self.music_player {
+            music_player.abort();
+        }
+        self.speaker.stop().await;
+    }
+}
+
 #[group]
-#[commands(bozo, unbozo)]
+#[commands(bozo, unbozo, say, musicchannel, musicbot, audiostats)]
 struct General;
 
 struct Handler;
@@ -71,21 +120,40 @@ impl EventHandler for Handler {
     async fn ready(&self, ctx: Context, ready: Ready) {
         println!("{} is connected!", ready.user.name);
 
-        let music_bot_channel_id: u64 = env::var("MUSIC_CMD_CHANNEL")
-            .expect("Couldn't find env MUSIC_CMD_CHANNEL!")
-            .parse()
-            .unwrap();
-        //let channel = ctx.cache.guild_channel(music_bot_channel_id).unwrap();
-        let read_guard = ctx.data.read().await;
-        let state = read_guard.get::<SharedState>();
-        let action_rx = state.unwrap().action_channel_tx.subscribe();
-        tokio::spawn(async move {
-            action_handler_loop(
-                ctx.http.clone(),
-                ChannelId::new(music_bot_channel_id),
-                action_rx,
+        // The native player is started per voice connection instead, see `bozo`.
+        if music::use_native_player() {
+            return;
+        }
+
+        let (guild_settings, profiles, action_rx) = {
+            let read_guard = ctx.data.read().await;
+            let state = read_guard.get::<SharedState>().unwrap();
+            (
+                state.guild_settings.clone(),
+                state.music_bot_profiles.clone(),
+                state.action_channel_tx.subscribe(),
             )
-            .await;
+        };
+
+        // MUSIC_CMD_CHANNEL used to be the only command channel, it still
+        // seeds the setting for its own guild if that guild has none.
+        if let Ok(channel_id) = env::var("MUSIC_CMD_CHANNEL") {
+            let channel_id = ChannelId::new(channel_id.parse().expect("Couldn't parse env MUSIC_CMD_CHANNEL!"));
+            match channel_id.to_channel(&ctx).await {
+                Ok(Channel::Guild(channel)) => {
+                    let mut settings = guild_settings.lock().unwrap();
+                    if settings.get(channel.guild_id).music_cmd_channel.is_none() {
+                        if let Err(e) = settings.update(channel.guild_id, |s| s.music_cmd_channel = Some(channel_id)) {
+                            println!("Couldn't save guild settings: {}", e);
+                        }
+                    }
+                }
+                _ => println!("MUSIC_CMD_CHANNEL {} isn't a guild channel, ignoring it", channel_id),
+            }
+        }
+
+        tokio::spawn(async move {
+            action_handler_loop(ctx.http.clone(), guild_settings, profiles, action_rx).await;
         });
     }
 }
@@ -93,14 +161,16 @@ impl EventHandler for Handler {
 #[derive(Clone)]
 struct Receiver {
     data: Arc<RwLock<TypeMap>>,
-    assistant: Arc<Mutex<DiscordAssistant>>,
+    guild_id: GuildId,
+    router: Arc<AudioRouter>,
 }
 
 impl Receiver {
-    pub fn new(data: Arc<RwLock<TypeMap>>, assistant: Arc<Mutex<DiscordAssistant>>) -> Self {
+    pub fn new(data: Arc<RwLock<TypeMap>>, guild_id: GuildId, router: Arc<AudioRouter>) -> Self {
         Self {
             data: data,
-            assistant: assistant,
+            guild_id: guild_id,
+            router: router,
         }
     }
 }
@@ -121,19 +191,8 @@ impl VoiceEventHandler for Receiver {
                 );
 
                 let mut write_guard = self.data.write().await;
-                if let Some(state) = write_guard.get_mut::<SharedState>() {
-                    if !state.users.contains_key(ssrc) {
-                        state.id_to_ssrc.insert(user_id.unwrap(), ssrc.clone());
-                        let (tx_listener_event, rx_listener_event) =
-                            mpsc::channel::<resampler::ListenerEvent>(32);
-                        state.users.insert(ssrc.clone(), tx_listener_event);
-
-                        let ssrc = ssrc.clone();
-                        let assistant = self.assistant.clone();
-                        tokio::spawn(async move {
-                            listener::listener_loop(rx_listener_event, assistant, ssrc).await;
-                        });
-                    }
+                if let Some(guild) = guild_state_mut(&mut write_guard, self.guild_id) {
+                    guild.listeners.on_speaking(*ssrc, *user_id);
                 }
             }
             // EventContext::SpeakingUpdate(data) => {
@@ -144,15 +203,37 @@ impl VoiceEventHandler for Receiver {
             //     );
             // }
             EventContext::VoiceTick(tick) => {
+                let mut crashed = Vec::new();
                 for (ssrc, data) in &tick.speaking {
-                    let decoded_voice = data.decoded_voice.as_ref().unwrap();
-                    let mut write_guard: tokio::sync::RwLockWriteGuard<'_, TypeMap> =
-                        self.data.write().await;
-                    if let Some(state) = write_guard.get_mut::<SharedState>() {
-                        if let Some(tx) = state.users.get(ssrc) {
-                            tx.send(resampler::ListenerEvent::AudioPacket(decoded_voice.clone()))
-                                .await
-                                .unwrap();
+                    // Packets that failed to decode carry no audio.
+                    let decoded_voice = match data.decoded_voice.as_ref() {
+                        Some(decoded_voice) => decoded_voice,
+                        None => continue,
+                    };
+                    let timing = data.packet.as_ref().map(|packet| {
+                        let rtp = packet.rtp();
+                        PacketTiming {
+                            sequence: rtp.get_sequence().0,
+                            timestamp: rtp.get_timestamp().0,
+                        }
+                    });
+                    let event = resampler::ListenerEvent::AudioPacket(decoded_voice.clone(), timing);
+                    if let RouteResult::Closed = self.router.route(*ssrc, event) {
+                        crashed.push(*ssrc);
+                    }
+                }
+                // Quiet users still count in ticks so their listeners keep time.
+                for ssrc in &tick.silent {
+                    if let RouteResult::Closed = self.router.route(*ssrc, resampler::ListenerEvent::Silence) {
+                        crashed.push(*ssrc);
+                    }
+                }
+
+                if !crashed.is_empty() {
+                    let mut write_guard = self.data.write().await;
+                    if let Some(guild) = guild_state_mut(&mut write_guard, self.guild_id) {
+                        for ssrc in crashed {
+                            guild.listeners.restart_if_crashed(ssrc);
                         }
                     }
                 }
@@ -161,12 +242,8 @@ impl VoiceEventHandler for Receiver {
                 println!("Client disconnected: user {:?}", user_id);
                 let mut write_guard: tokio::sync::RwLockWriteGuard<'_, TypeMap> =
                     self.data.write().await;
-                if let Some(state) = write_guard.get_mut::<SharedState>() {
-                    if let Some(ssrc) = state.id_to_ssrc.get(user_id) {
-                        if let Some(tx) = state.users.get(ssrc) {
-                            tx.send(resampler::ListenerEvent::Disconnect).await.unwrap();
-                        }
-                    }
+                if let Some(guild) = guild_state_mut(&mut write_guard, self.guild_id) {
+                    guild.listeners.on_disconnect(*user_id);
                 }
             }
             _ => (),
@@ -176,6 +253,14 @@ impl VoiceEventHandler for Receiver {
     }
 }
 
+fn guild_state(data: &TypeMap, guild_id: GuildId) -> Option<&GuildState> {
+    data.get::<SharedState>()?.guilds.get(&guild_id)
+}
+
+fn guild_state_mut(data: &mut TypeMap, guild_id: GuildId) -> Option<&mut GuildState> {
+    data.get_mut::<SharedState>()?.guilds.get_mut(&guild_id)
+}
+
 fn find_channel_from_user(
     ctx: &Context,
     user: &User,
@@ -210,41 +295,73 @@ async fn bozo(ctx: &Context, msg: &Message, mut _args: Args) -> CommandResult {
                 .expect("Songbird Voice client placed in at initialization.")
                 .clone();
 
-            let assistant: Arc<Mutex<DiscordAssistant>> = {
+            let guild_id = msg.guild_id.unwrap();
+
+            // Summoning the bot again starts the guild over with a fresh assistant.
+            remove_guild_state(ctx, guild_id).await;
+
+            let (router, mixer) = {
                 let mut data_guard = ctx.data.write().await;
                 if let Some(state) = data_guard.get_mut::<SharedState>() {
-                    Arc::new(Mutex::new(
+                    let mixer = DuckingMixer::new(state.duck_settings);
+                    let speaker = AgentSpeaker::new(
+                        manager.clone(),
+                        guild_id.into(),
+                        state.text_to_speech.clone(),
+                        state.sound_store.clone(),
+                        mixer.clone(),
+                    );
+                    let assistant = Arc::new(Mutex::new(
                         DiscordAssistant::new(
-                            state.oai_client.clone(),
-                            AgentSpeaker::new(
-                                manager.clone(),
-                                msg.guild_id.unwrap().into(),
-                                state.sound_store.clone(),
-                            ),
+                            state.llm.clone(),
+                            state.speech_to_text.clone(),
+                            speaker.clone(),
+                            guild_id,
                             state.action_channel_tx.clone(),
                         )
                         .await,
-                    ))
+                    ));
+                    let guild = GuildState::new(assistant, speaker, state.speech_to_text.clone(), state.dsp_pool.clone());
+                    let router = guild.listeners.router();
+                    state.guilds.insert(guild_id, guild);
+                    (router, mixer)
                 } else {
                     bail!("couldn't create discord assistant for channel!")
                 }
             };
 
-            if let Ok(join_lock) = manager.join(msg.guild_id.unwrap(), channel_id).await {
+            if let Ok(join_lock) = manager.join(guild_id, channel_id).await {
                 // NOTE: this skips listening for the actual connection result.
                 let mut handler = join_lock.lock().await;
 
-                let receiver = Receiver::new(ctx.data.clone(), assistant.clone());
+                // Rejoining keeps the call, drop the receiver of the previous assistant.
+                handler.remove_all_global_events();
+
+                let receiver = Receiver::new(ctx.data.clone(), guild_id, router);
 
                 handler.add_global_event(CoreEvent::SpeakingStateUpdate.into(), receiver.clone());
                 handler.add_global_event(CoreEvent::VoiceTick.into(), receiver.clone());
                 handler.add_global_event(CoreEvent::ClientDisconnect.into(), receiver.clone());
 
+                if music::use_native_player() {
+                    let player = MusicPlayer::new(manager.clone(), guild_id.into(), mixer);
+                    let mut data_guard = ctx.data.write().await;
+                    if let Some(state) = data_guard.get_mut::<SharedState>() {
+                        let action_rx = state.action_channel_tx.subscribe();
+                        if let Some(guild) = state.guilds.get_mut(&guild_id) {
+                            guild.music_player = Some(tokio::spawn(async move {
+                                music::music_player_loop(player, action_rx).await;
+                            }));
+                        }
+                    }
+                }
+
                 msg.channel_id
                     .say(&ctx.http, &format!("Joined {}", channel_id.mention()))
                     .await
                     .unwrap();
             } else {
+                remove_guild_state(ctx, guild_id).await;
                 msg.channel_id
                     .say(&ctx.http, "Error joining the channel")
                     .await
@@ -270,6 +387,8 @@ async fn unbozo(ctx: &Context, msg: &Message) -> CommandResult {
         .expect("Songbird Voice client placed in at initialization.");
     let has_handler = manager.get(guild_id).is_some();
 
+    remove_guild_state(ctx, guild_id).await;
+
     if has_handler {
         if let Err(e) = manager.remove(guild_id).await {
             msg.channel_id
@@ -289,11 +408,180 @@ async fn unbozo(ctx: &Context, msg: &Message) -> CommandResult {
     Ok(())
 }
 
+async fn remove_guild_state(ctx: &Context, guild_id: GuildId) {
+    let guild = {
+        let mut data_guard = ctx.data.write().await;
+        match data_guard.get_mut::<SharedState>() {
+            Some(state) => state.guilds.remove(&guild_id),
+            None => None,
+        }
+    };
+    if let Some(guild) = guild {
+        guild.shutdown().await;
+    }
+}
+
+// Has the assistant read out a message once it's done talking.
+#[command]
+#[only_in(guilds)]
+async fn say(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let text = args.rest().trim();
+    if text.is_empty() {
+        msg.reply(ctx, "Usage: ~say <message>").await?;
+        return Ok(());
+    }
+
+    let speaker = {
+        let data_guard = ctx.data.read().await;
+        guild_state(&data_guard, guild_id).map(|guild| guild.speaker.clone())
+    };
+    match speaker {
+        Some(speaker) => {
+            speaker.announce(text).await;
+        }
+        None => {
+            msg.reply(ctx, "Not in a voice channel").await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Reports how much audio each listener has been handed and how much it
+// missed, and how long its frames take to process.
+#[command]
+#[only_in(guilds)]
+async fn audiostats(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let stats = {
+        let data_guard = ctx.data.read().await;
+        guild_state(&data_guard, guild_id)
+            .map(|guild| (guild.listeners.router().stats(), guild.listeners.dsp_stats()))
+    };
+
+    let report = match stats {
+        Some((route_stats, dsp_stats)) => {
+            let mut lines: Vec<String> = route_stats
+                .iter()
+                .map(|stats| format!("SSRC {}: {} delivered, {} dropped", stats.ssrc, stats.delivered, stats.dropped))
+                .collect();
+            lines.extend(dsp_stats.iter().map(|(ssrc, stats)| {
+                format!(
+                    "SSRC {}: {} frames, {:?} avg / {:?} max processing, {:?} avg / {:?} max queued",
+                    ssrc,
+                    stats.frames,
+                    stats.busy_average(),
+                    stats.busy_max,
+                    stats.wait_average(),
+                    stats.wait_max
+                )
+            }));
+            if lines.is_empty() {
+                "Nobody is being listened to".to_string()
+            } else {
+                lines.join("\n")
+            }
+        }
+        None => "Not in a voice channel".to_string(),
+    };
+    msg.channel_id.say(&ctx.http, report).await?;
+
+    Ok(())
+}
+
+// Sets the channel the music bot takes commands in, the current channel
+// unless another one is mentioned.
+#[command]
+#[only_in(guilds)]
+async fn musicchannel(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let channel_id = if args.is_empty() {
+        msg.channel_id
+    } else {
+        match args.single::<ChannelId>() {
+            Ok(channel_id) => channel_id,
+            Err(_) => {
+                msg.reply(ctx, "Usage: ~musicchannel [#channel]").await?;
+                return Ok(());
+            }
+        }
+    };
+
+    let in_guild = match msg.guild(&ctx.cache) {
+        Some(guild) => guild.channels.contains_key(&channel_id),
+        None => false,
+    };
+    if !in_guild {
+        msg.reply(ctx, "That channel isn't in this server").await?;
+        return Ok(());
+    }
+
+    let guild_settings = {
+        let data_guard = ctx.data.read().await;
+        data_guard.get::<SharedState>().unwrap().guild_settings.clone()
+    };
+    let result = guild_settings
+        .lock()
+        .unwrap()
+        .update(guild_id, |settings| settings.music_cmd_channel = Some(channel_id));
+    match result {
+        Ok(_) => {
+            msg.channel_id
+                .say(&ctx.http, format!("Music commands go to {}", channel_id.mention()))
+                .await?;
+        }
+        Err(e) => {
+            msg.reply(ctx, format!("Couldn't save the music channel: {}", e)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Picks the music bot profile commands are written for.
+#[command]
+#[only_in(guilds)]
+async fn musicbot(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guild_id = msg.guild_id.unwrap();
+    let (guild_settings, profiles) = {
+        let data_guard = ctx.data.read().await;
+        let state = data_guard.get::<SharedState>().unwrap();
+        (state.guild_settings.clone(), state.music_bot_profiles.clone())
+    };
+
+    let name = args.rest().trim();
+    if profiles.get(name).is_none() {
+        msg.reply(ctx, format!("Usage: ~musicbot <{}>", profiles.names().join("|")))
+            .await?;
+        return Ok(());
+    }
+
+    let result = guild_settings
+        .lock()
+        .unwrap()
+        .update(guild_id, |settings| settings.music_bot_profile = Some(name.to_string()));
+    match result {
+        Ok(_) => {
+            msg.channel_id
+                .say(&ctx.http, format!("Using the {} music bot profile", name))
+                .await?;
+        }
+        Err(e) => {
+            msg.reply(ctx, format!("Couldn't save the music bot profile: {}", e)).await?;
+        }
+    }
+
+    Ok(())
+}
+
 pub async fn init_serenity() -> Client {
     let framework = StandardFramework::new().group(&GENERAL_GROUP);
     framework.configure(Configuration::new().prefix("~"));
     let intents = GatewayIntents::non_privileged() | GatewayIntents::MESSAGE_CONTENT;
-    let songbird_config = Config::default().decode_mode(DecodeMode::Decode);
+    let songbird_config = Config::default()
+        .decode_mode(DecodeMode::Decode)
+        .decode_channels(resampler::decode_channels());
     let token = env::var("DISCORD_TOKEN").expect("Couldn't find env DISCORD_TOKEN!");
     Client::builder(token, intents)
         .event_handler(Handler)
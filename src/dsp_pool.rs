@@ -0,0 +1,114 @@
+use std::{
+    env,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex as SyncMutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{mpsc, oneshot};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Timings of the frames one listener has pushed through the pool.
+#[derive(Clone, Default)]
+pub struct DspStats {
+    pub frames: u64,
+    // Time spent waiting for a free worker.
+    pub wait_total: Duration,
+    pub wait_max: Duration,
+    // Time spent processing.
+    pub busy_total: Duration,
+    pub busy_max: Duration,
+}
+
+impl DspStats {
+    fn record(&mut self, wait: Duration, busy: Duration) {
+        self.frames += 1;
+        self.wait_total += wait;
+        self.wait_max = self.wait_max.max(wait);
+        self.busy_total += busy;
+        self.busy_max = self.busy_max.max(busy);
+    }
+
+    pub fn wait_average(&self) -> Duration {
+        self.wait_total / self.frames.max(1) as u32
+    }
+
+    pub fn busy_average(&self) -> Duration {
+        self.busy_total / self.frames.max(1) as u32
+    }
+}
+
+// A fixed set of threads for the CPU heavy audio processing (resampling, wake
+// word, VAD and on-device transcription), so it never runs on, and starves,
+// the async runtime. The queue is bounded, listeners wait for room when the
+// workers can't keep up.
+pub struct DspPool {
+    tx: mpsc::Sender<Job>,
+}
+
+impl DspPool {
+    pub fn new(threads: usize, queue_len: usize) -> Self {
+        let (tx, rx) = mpsc::channel::<Job>(queue_len);
+        let rx = Arc::new(SyncMutex::new(rx));
+
+        for i in 0..threads {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("dsp-{}", i))
+                .spawn(move || loop {
+                    let job = rx.lock().unwrap().blocking_recv();
+                    match job {
+                        // A panicking job only fails its own listener, the worker carries on.
+                        Some(job) => {
+                            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                        }
+                        None => break,
+                    }
+                })
+                .expect("Couldn't spawn DSP worker!");
+        }
+
+        DspPool { tx: tx }
+    }
+
+    // Runs `job` on a worker and waits for its result, recording its timings
+    // in `stats`. Panics if the job did.
+    pub async fn run<T, F>(&self, stats: &Arc<SyncMutex<DspStats>>, job: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (result_tx, result_rx) = oneshot::channel();
+        let stats = stats.clone();
+        let queued = Instant::now();
+        let job: Job = Box::new(move || {
+            let wait = queued.elapsed();
+            let started = Instant::now();
+            let result = job();
+            stats.lock().unwrap().record(wait, started.elapsed());
+            let _ = result_tx.send(result);
+        });
+
+        if self.tx.send(job).await.is_err() {
+            panic!("DSP pool has shut down");
+        }
+        result_rx.await.expect("DSP job panicked")
+    }
+}
+
+// DSP_THREADS sets the number of workers, half the available cores by
+// default. DSP_QUEUE_LEN bounds the frames waiting for a worker.
+pub fn init_dsp_pool() -> Arc<DspPool> {
+    let default_threads = thread::available_parallelism().map(|n| n.get() / 2).unwrap_or(1).max(1);
+    let threads: usize = match env::var("DSP_THREADS") {
+        Ok(threads) => threads.parse().expect("Couldn't parse env DSP_THREADS!"),
+        Err(_) => default_threads,
+    };
+    let queue_len: usize = env::var("DSP_QUEUE_LEN")
+        .unwrap_or("64".into())
+        .parse()
+        .expect("Couldn't parse env DSP_QUEUE_LEN!");
+    Arc::new(DspPool::new(threads, queue_len))
+}
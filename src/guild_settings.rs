@@ -0,0 +1,52 @@
+use std::{collections::HashMap, env, error::Error, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serenity::model::id::{ChannelId, GuildId};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GuildSettings {
+    // Channel the music bot listens to for commands.
+    pub music_cmd_channel: Option<ChannelId>,
+    // Name of the music bot profile, the default profile if unset.
+    pub music_bot_profile: Option<String>,
+}
+
+// Settings for every guild, saved as JSON whenever they change.
+pub struct GuildSettingsStore {
+    path: PathBuf,
+    guilds: HashMap<GuildId, GuildSettings>,
+}
+
+impl GuildSettingsStore {
+    pub fn load(path: PathBuf) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let guilds = if path.exists() {
+            serde_json::from_str(&std::fs::read_to_string(&path)?)?
+        } else {
+            HashMap::new()
+        };
+        Ok(GuildSettingsStore {
+            path: path,
+            guilds: guilds,
+        })
+    }
+
+    pub fn get(&self, guild_id: GuildId) -> GuildSettings {
+        self.guilds.get(&guild_id).cloned().unwrap_or_default()
+    }
+
+    pub fn update<F: FnOnce(&mut GuildSettings)>(
+        &mut self,
+        guild_id: GuildId,
+        update: F,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        update(self.guilds.entry(guild_id).or_default());
+        std::fs::write(&self.path, serde_json::to_string_pretty(&self.guilds)?)?;
+        Ok(())
+    }
+}
+
+// GUILD_SETTINGS_PATH is where settings are kept, guild_settings.json by default.
+pub fn init_guild_settings() -> GuildSettingsStore {
+    let path = env::var("GUILD_SETTINGS_PATH").unwrap_or("guild_settings.json".into());
+    GuildSettingsStore::load(PathBuf::from(path)).expect("Couldn't load guild settings!")
+}
@@ -1,79 +1,190 @@
-use std::{time::Duration, sync::Arc, env, io::Cursor};
-use tokio::{sync::{mpsc, Mutex}, time::Instant};
-use wav::WAV_FORMAT_PCM;
+use std::{time::Duration, sync::{Arc, Mutex as SyncMutex}, env};
+use tokio::{sync::{mpsc, Mutex}, task::JoinHandle, time::Instant};
 
-use cobra::Cobra;
-use porcupine::{PorcupineBuilder, Porcupine};
-use async_openai::types::AudioInput;
+use cheetah::{Cheetah, CheetahBuilder};
 
-use crate::{assistant::DiscordAssistant, resampler::{ListenerEvent, self}};
+use crate::{assistant::{DiscordAssistant, Utterance}, dsp_pool::{DspPool, DspStats}, resampler::{self, ListenerEvent, PacketReader, Resampler, DECODE_SAMPLE_RATE}, speech_to_text::{encode_wav, SpeechToText}, vad::{self, VoiceActivityDetector}, wake_word::{self, WakeWordDetector}};
 
+// All engines consume 16kHz mono audio in 32ms frames.
+const SAMPLE_RATE: u32 = 16_000;
+const FRAME_LENGTH: usize = 512;
+
+// The reply being worked out for whoever has the assistant's attention. It
+// lives outside the listeners so it can be stopped when the guild goes away.
+pub type ResponseTask = Arc<SyncMutex<Option<JoinHandle<()>>>>;
+
+#[derive(Clone, Copy)]
 enum ConversationState {
     Detection,
     Listening,
     Responding,
 }
 
+// Everything CPU heavy a listener owns. It is moved onto a DSP pool worker for
+// each frame and handed back with the results.
+struct ListenerDsp {
+    resampler: Resampler,
+    wake_word: Box<dyn WakeWordDetector>,
+    vad: Box<dyn VoiceActivityDetector>,
+    cheetah: Option<Cheetah>,
+}
+
+// What a frame held, only the engines the current state needs are run.
+#[derive(Default)]
+struct FrameAnalysis {
+    frame: Vec<i16>,
+    wake_word: bool,
+    is_speech: bool,
+    transcript: String,
+    endpoint: bool,
+}
+
+impl ListenerDsp {
+    fn process(&mut self, input: Vec<f64>, state: ConversationState) -> FrameAnalysis {
+        let mut analysis = FrameAnalysis::default();
+        analysis.frame.reserve(FRAME_LENGTH);
+        self.resampler.resample(&input, &mut analysis.frame);
+
+        match state {
+            ConversationState::Detection => {
+                analysis.wake_word = self.wake_word.process(&analysis.frame);
+                if analysis.wake_word {
+                    self.wake_word.reset();
+                }
+            },
+            ConversationState::Listening => {
+                analysis.is_speech = self.vad.is_speech(&analysis.frame);
+                if let Some(cheetah) = &self.cheetah {
+                    match cheetah.process(&analysis.frame) {
+                        Ok(partial) => {
+                            analysis.transcript = partial.transcript;
+                            analysis.endpoint = partial.is_endpoint;
+                        },
+                        Err(e) => {
+                            println!("Cheetah error: {}", e);
+                        }
+                    }
+                }
+            },
+            ConversationState::Responding => {
+                // Only for noticing the user talking over the reply.
+                analysis.is_speech = self.vad.is_speech(&analysis.frame);
+            }
+        }
+
+        analysis
+    }
+
+    // Transcribes audio heard before the listener switched to transcribing,
+    // e.g. the start of a user talking over a reply.
+    fn transcribe_frames(&mut self, frames: Vec<i16>) -> String {
+        let mut transcript = String::new();
+        if let Some(cheetah) = &self.cheetah {
+            for frame in frames.chunks_exact(FRAME_LENGTH) {
+                match cheetah.process(frame) {
+                    Ok(partial) => transcript.push_str(&partial.transcript),
+                    Err(e) => println!("Cheetah error: {}", e)
+                }
+            }
+        }
+        transcript
+    }
+
+    fn flush_transcript(&mut self) -> String {
+        match &self.cheetah {
+            Some(cheetah) => match cheetah.flush() {
+                Ok(remaining) => remaining.transcript,
+                Err(e) => {
+                    println!("Cheetah error: {}", e);
+                    String::new()
+                }
+            },
+            None => String::new()
+        }
+    }
+}
+
 pub async fn listener_loop(
-    rx_48khz: mpsc::Receiver<ListenerEvent>,
+    rx_voice: mpsc::Receiver<ListenerEvent>,
     assistant: Arc<Mutex<DiscordAssistant>>,
-    ssrc: u32) {
+    stt: Arc<dyn SpeechToText>,
+    ssrc: u32,
+    dsp_pool: Arc<DspPool>,
+    dsp_stats: Arc<SyncMutex<DspStats>>,
+    response_task: ResponseTask) {
 
-    let porcupine = init_porcupine();
-    let cobra: Cobra = init_cobra();
+    let cheetah: Option<Cheetah> = init_cheetah();
+    let streaming = cheetah.is_some();
 
-    assert!(porcupine.sample_rate() == cobra.sample_rate());
-    assert!(cobra.frame_length() == porcupine.frame_length());
-    let sample_rate = porcupine.sample_rate();
+    if let Some(cheetah) = &cheetah {
+        assert!(cheetah.sample_rate() == SAMPLE_RATE);
+        assert!(cheetah.frame_length() as usize == FRAME_LENGTH);
+    }
 
-    let mut resampler = resampler::Resampler::new(rx_48khz, sample_rate as f64, porcupine.frame_length() as usize, 2);
-    let mut input_frame = Vec::<i16>::with_capacity(porcupine.frame_length() as  usize);
+    let mut dsp = ListenerDsp {
+        resampler: Resampler::new(DECODE_SAMPLE_RATE as f64, SAMPLE_RATE as f64, FRAME_LENGTH),
+        wake_word: wake_word::init_wake_word_detector(stt, SAMPLE_RATE, FRAME_LENGTH),
+        vad: vad::init_voice_activity_detector(SAMPLE_RATE, FRAME_LENGTH),
+        cheetah: cheetah,
+    };
+    let mut packet_reader = PacketReader::new(
+        rx_voice,
+        DECODE_SAMPLE_RATE,
+        resampler::channel_count(resampler::decode_channels()));
 
     let mut conversation_state = ConversationState::Detection;
     let mut time_listening: Option<Instant> = None;
     let mut time_not_speaking: Option<Instant> = None;
     let mut transcription_audio = Vec::<i16>::default();
+    let mut transcript = String::new();
+    let barge_in_frames = barge_in_frames();
+    let mut barge_in_audio = Vec::<i16>::default();
+    let mut barge_in_speech_frames = 0;
 
     loop {
         // Consume packets
-        if !resampler.read_frames_resample(&mut input_frame).await {
+        let input = match packet_reader.read_frames(dsp.resampler.input_frames_next()).await {
+            Some(input) => input,
             // Client disconnected!
-            break;
-        } 
+            None => break
+        };
+
+        let state = conversation_state;
+        let (returned_dsp, mut analysis) = dsp_pool.run(&dsp_stats, move || {
+            let analysis = dsp.process(input, state);
+            (dsp, analysis)
+        }).await;
+        dsp = returned_dsp;
 
         match conversation_state {
             ConversationState::Detection => {
                 // Listening in for the trigger word.
-                match porcupine.process(&input_frame) {
-                    Ok(keyword_index) => {
-                        if keyword_index >= 0 {
-                            // Hit the trigger word, start speech to text.
-                            println!("Trigger word detected!");
-
-                            // Only one person can talk to the assistant at a time!
-                            if let Ok(mut guard) = assistant.try_lock() {
-                                if guard.try_grab_attention(ssrc) {
-                                    guard.flush().await;
-                                    guard.speaker.acknowledge().await;
-
-                                    conversation_state = ConversationState::Listening;
-                                    println!("listening");
-                                    time_not_speaking = None;
-                                    transcription_audio.clear();
-                                }
-                            }
+                if analysis.wake_word {
+                    // Hit the trigger word, start speech to text.
+                    println!("Trigger word detected!");
+
+                    // Only one person can talk to the assistant at a time!
+                    if let Ok(mut guard) = assistant.try_lock() {
+                        if guard.try_grab_attention(ssrc) {
+                            guard.begin_conversation().await;
+                            guard.speaker.acknowledge().await;
+
+                            conversation_state = ConversationState::Listening;
+                            println!("listening");
+                            time_not_speaking = None;
+                            transcription_audio.clear();
+                            transcript.clear();
                         }
-                    },
-                    Err(e) => {
-                        println!("Porcupine error: {}", e);
                     }
                 }
             },
             ConversationState::Listening => {
-                let speaking_confidence = cobra.process(&input_frame).unwrap();
-                transcription_audio.append(&mut input_frame);
+                let is_speech = analysis.is_speech;
+                let endpoint_detected = analysis.endpoint;
+                transcript.push_str(&analysis.transcript);
+                transcription_audio.append(&mut analysis.frame);
 
-                if speaking_confidence < 0.75 {
+                if !is_speech {
                     if time_not_speaking.is_none() {
                         time_not_speaking = Some(Instant::now());
                     }
@@ -82,53 +193,131 @@ pub async fn listener_loop(
                     time_not_speaking = None;
                 }
 
-                if let Some(time_not_speaking_instant) = time_not_speaking {
-                    if time_not_speaking_instant.elapsed() >= Duration::from_secs(3) {
-
-                        if let Some(time_listening_instant) = time_listening {
-                            let listening_speaking_delta = time_listening_instant.elapsed() - time_not_speaking_instant.elapsed();
-                            if listening_speaking_delta.as_millis() < 500 {
-                                conversation_state = ConversationState::Detection;
-                                println!("detection");
-                                transcription_audio.clear();
-                                time_not_speaking = None;
-                                time_listening = None;
-                                let mut guard: tokio::sync::MutexGuard<'_, DiscordAssistant> = assistant.lock().await;
-                                guard.try_clear_attention(ssrc);
-                                continue;
-                            }
-                        }
+                let silence_timed_out = match time_not_speaking {
+                    Some(time_not_speaking_instant) => time_not_speaking_instant.elapsed() >= Duration::from_secs(3),
+                    None => false
+                };
+
+                if endpoint_detected || silence_timed_out {
+                    if streaming {
+                        let (returned_dsp, remaining) = dsp_pool.run(&dsp_stats, move || {
+                            let remaining = dsp.flush_transcript();
+                            (dsp, remaining)
+                        }).await;
+                        dsp = returned_dsp;
+                        transcript.push_str(&remaining);
+                    }
 
-                        conversation_state = ConversationState::Responding;
-                        println!("responding");
+                    let nothing_said = if streaming {
+                        transcript.trim().is_empty()
+                    } else {
+                        match (time_listening, time_not_speaking) {
+                            (Some(time_listening_instant), Some(time_not_speaking_instant)) => {
+                                let listening_speaking_delta = time_listening_instant.elapsed() - time_not_speaking_instant.elapsed();
+                                listening_speaking_delta.as_millis() < 500
+                            },
+                            _ => false
+                        }
+                    };
 
-                        let guard: tokio::sync::MutexGuard<'_, DiscordAssistant> = assistant.lock().await;
-                        guard.set_responding();
-    
-                        // Play waiting sound
-                        guard.speaker.start_ping().await;
-        
-                        let transcription_buf = transcription_audio.clone();
+                    if nothing_said {
+                        conversation_state = ConversationState::Detection;
+                        println!("detection");
                         transcription_audio.clear();
-
-                        // Prompt the agent and respond
-                        let mut bytes = Cursor::new(vec![]);
-                        let bit_depth = wav::bit_depth::BitDepth::Sixteen(transcription_buf);
-                        let header = wav::Header::new(WAV_FORMAT_PCM, 1, sample_rate, 16);
-                        wav::write(header, &bit_depth, &mut bytes).unwrap();
-                        let audio_input: AudioInput = AudioInput::from_bytes("dummy.wav".into(), bytes::Bytes::from(bytes.into_inner()));
-                        let assistant = assistant.clone();
-                        tokio::spawn(async move {
-                            let mut guard: tokio::sync::MutexGuard<'_, DiscordAssistant> = assistant.lock().await;
-                            guard.send_message(audio_input).await;
-                        });
-    
+                        transcript.clear();
+                        time_not_speaking = None;
+                        time_listening = None;
+                        let mut guard: tokio::sync::MutexGuard<'_, DiscordAssistant> = assistant.lock().await;
+                        guard.try_clear_attention(ssrc);
+                        continue;
                     }
+
+                    conversation_state = ConversationState::Responding;
+                    println!("responding");
+
+                    let guard: tokio::sync::MutexGuard<'_, DiscordAssistant> = assistant.lock().await;
+                    guard.set_responding();
+
+                    // Play waiting sound
+                    guard.speaker.start_ping().await;
+
+                    let transcription_buf = transcription_audio.clone();
+                    transcription_audio.clear();
+
+                    // Prompt the agent and respond
+                    let utterance = if streaming {
+                        Utterance::Transcript(std::mem::take(&mut transcript).trim().to_string())
+                    } else {
+                        Utterance::Audio(encode_wav(transcription_buf, SAMPLE_RATE))
+                    };
+                    let assistant = assistant.clone();
+                    *response_task.lock().unwrap() = Some(tokio::spawn(async move {
+                        let mut guard: tokio::sync::MutexGuard<'_, DiscordAssistant> = assistant.lock().await;
+                        guard.send_message(utterance).await;
+                    }));
+                    barge_in_audio.clear();
+                    barge_in_speech_frames = 0;
                 }
             },
             ConversationState::Responding => {
-                let guard = assistant.lock().await;
+                if barge_in_frames > 0 {
+                    if analysis.is_speech {
+                        // Kept so the start of what the user says isn't lost.
+                        barge_in_audio.append(&mut analysis.frame);
+                        barge_in_speech_frames += 1;
+                    } else {
+                        barge_in_audio.clear();
+                        barge_in_speech_frames = 0;
+                    }
+                }
+
+                if barge_in_frames > 0 && barge_in_speech_frames >= barge_in_frames {
+                    println!("barge in");
+                    let task = response_task.lock().unwrap().take();
+                    if let Some(task) = task {
+                        task.abort();
+                    }
+                    barge_in_speech_frames = 0;
+
+                    let still_ours = {
+                        let mut guard = assistant.lock().await;
+                        guard.interrupt().await;
+                        guard.get_attention_id() == Some(ssrc)
+                    };
+                    if !still_ours {
+                        // The reply had already ended the conversation.
+                        barge_in_audio.clear();
+                        conversation_state = ConversationState::Detection;
+                        println!("detection");
+                        continue;
+                    }
+
+                    time_not_speaking = None;
+                    time_listening = Some(Instant::now());
+                    transcript.clear();
+                    transcription_audio = std::mem::take(&mut barge_in_audio);
+                    if streaming {
+                        let frames = transcription_audio.clone();
+                        let (returned_dsp, heard) = dsp_pool.run(&dsp_stats, move || {
+                            let heard = dsp.transcribe_frames(frames);
+                            (dsp, heard)
+                        }).await;
+                        dsp = returned_dsp;
+                        transcript.push_str(&heard);
+                    }
+                    conversation_state = ConversationState::Listening;
+                    println!("listening");
+                    continue;
+                }
+
+                // The assistant stays locked while a reply is being worked
+                // out, keep listening for a barge in meanwhile.
+                let guard = match assistant.try_lock() {
+                    Ok(guard) => guard,
+                    Err(_) => continue
+                };
                 if !guard.is_responding().await {
+                    *response_task.lock().unwrap() = None;
                     time_not_speaking = None;
                     time_listening = Some(Instant::now());
                     transcription_audio.clear();
@@ -151,17 +340,32 @@ pub async fn listener_loop(
     }
 }
 
-fn init_porcupine() -> Porcupine {
-    let mut ppn_path = std::env::current_dir().expect("Couldn't get CWD!");
-    ppn_path.push("resources");
-    ppn_path.push("hey-bozo_en_windows_v2_2_0.ppn");
-
-    PorcupineBuilder::new_with_keyword_paths(env::var("PV_KEY").expect("Couldn't get env PV_KEY!"), &[ppn_path])
-        .init()
-        .expect("Couldn't init porcupine!")
+// BARGE_IN_FRAMES is how many frames in a row the user has to speak over a
+// reply to cut it off, 8 (about a quarter second) by default. 0 turns barging
+// in off.
+fn barge_in_frames() -> usize {
+    env::var("BARGE_IN_FRAMES")
+        .unwrap_or("8".into())
+        .parse()
+        .expect("Couldn't parse env BARGE_IN_FRAMES!")
 }
 
-fn init_cobra() -> Cobra {
-    Cobra::new(env::var("PV_KEY").expect("Couldn't get env PV_KEY!"))
-        .expect("Unable to create Cheetah")
-}
\ No newline at end of file
+// STT_STREAMING=cheetah transcribes on-device while the user speaks instead of
+// uploading the whole utterance once they go quiet.
+fn init_cheetah() -> Option<Cheetah> {
+    match env::var("STT_STREAMING") {
+        Ok(engine) if engine == "cheetah" => {
+            let endpoint_duration_sec: f32 = env::var("CHEETAH_ENDPOINT_SECS")
+                .unwrap_or("1.0".into())
+                .parse()
+                .expect("Couldn't parse env CHEETAH_ENDPOINT_SECS!");
+            Some(CheetahBuilder::new()
+                .access_key(env::var("PV_KEY").expect("Couldn't get env PV_KEY!"))
+                .endpoint_duration_sec(endpoint_duration_sec)
+                .enable_automatic_punctuation(true)
+                .init()
+                .expect("Unable to create Cheetah"))
+        },
+        _ => None
+    }
+}
@@ -0,0 +1,287 @@
+use std::{collections::HashMap, sync::{Arc, Mutex as SyncMutex}};
+
+use songbird::model::id::UserId;
+use tokio::{
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
+
+use crate::{assistant::DiscordAssistant, audio_router::AudioRouter, dsp_pool::{DspPool, DspStats}, listener::{self, ResponseTask}, resampler::ListenerEvent, speech_to_text::SpeechToText};
+
+// Gives up on a listener that keeps crashing, it is started again the next
+// time its user starts speaking.
+const MAX_RESTARTS: u32 = 3;
+
+struct ListenerHandle {
+    user_id: Option<UserId>,
+    tx: mpsc::Sender<ListenerEvent>,
+    task: JoinHandle<()>,
+    restarts: u32,
+    dsp_stats: Arc<SyncMutex<DspStats>>,
+}
+
+// Owns the listener task of every SSRC in a voice connection. Listeners only
+// exit on their own when they are told to disconnect, so a finished task that
+// is still registered has crashed and gets restarted.
+pub struct ListenerManager {
+    assistant: Arc<Mutex<DiscordAssistant>>,
+    speech_to_text: Arc<dyn SpeechToText>,
+    dsp_pool: Arc<DspPool>,
+    listeners: HashMap<u32, ListenerHandle>,
+    id_to_ssrc: HashMap<UserId, u32>,
+    router: Arc<AudioRouter>,
+    // Only whoever has the assistant's attention gets a reply, so the
+    // listeners share one.
+    response_task: ResponseTask,
+}
+
+impl ListenerManager {
+    pub fn new(
+        assistant: Arc<Mutex<DiscordAssistant>>,
+        speech_to_text: Arc<dyn SpeechToText>,
+        dsp_pool: Arc<DspPool>,
+    ) -> Self {
+        ListenerManager {
+            assistant: assistant,
+            speech_to_text: speech_to_text,
+            dsp_pool: dsp_pool,
+            listeners: HashMap::default(),
+            id_to_ssrc: HashMap::default(),
+            router: Arc::new(AudioRouter::default()),
+            response_task: ResponseTask::default(),
+        }
+    }
+
+    // Audio goes straight through the router, the manager only has to be
+    // involved when listeners come and go.
+    pub fn router(&self) -> Arc<AudioRouter> {
+        self.router.clone()
+    }
+
+    pub fn dsp_stats(&self) -> Vec<(u32, DspStats)> {
+        let mut stats: Vec<(u32, DspStats)> = self
+            .listeners
+            .iter()
+            .map(|(ssrc, listener)| (*ssrc, listener.dsp_stats.lock().unwrap().clone()))
+            .collect();
+        stats.sort_by_key(|(ssrc, _)| *ssrc);
+        stats
+    }
+
+    pub fn on_speaking(&mut self, ssrc: u32, user_id: Option<UserId>) {
+        if let Some(user_id) = user_id {
+            // A user that rejoins is handed a new SSRC, the old one is gone for good.
+            if let Some(old_ssrc) = self.id_to_ssrc.insert(user_id, ssrc) {
+                if old_ssrc != ssrc {
+                    println!("User {:?} moved from SSRC {} to {}", user_id, old_ssrc, ssrc);
+                    self.remove(old_ssrc);
+                }
+            }
+        }
+
+        if let Some(listener) = self.listeners.get_mut(&ssrc) {
+            // Discord reuses SSRCs, a different user means a different conversation.
+            if user_id.is_some() && listener.user_id.is_some() && listener.user_id != user_id {
+                self.remove(ssrc);
+            } else if listener.user_id.is_none() {
+                listener.user_id = user_id;
+            }
+        }
+
+        if !self.listeners.contains_key(&ssrc) {
+            self.spawn(ssrc, user_id, 0);
+        } else {
+            self.restart_if_crashed(ssrc);
+        }
+    }
+
+    pub fn on_disconnect(&mut self, user_id: UserId) {
+        if let Some(ssrc) = self.id_to_ssrc.remove(&user_id) {
+            self.remove(ssrc);
+        }
+    }
+
+    pub fn shutdown(&mut self) {
+        let ssrcs: Vec<u32> = self.listeners.keys().cloned().collect();
+        for ssrc in ssrcs {
+            self.remove(ssrc);
+        }
+        self.id_to_ssrc.clear();
+        // Don't leave a reply being worked out for a guild that's gone.
+        if let Some(task) = self.response_task.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+
+    fn spawn(&mut self, ssrc: u32, user_id: Option<UserId>, restarts: u32) {
+        let (tx, rx) = mpsc::channel::<ListenerEvent>(32);
+        let assistant = self.assistant.clone();
+        let speech_to_text = self.speech_to_text.clone();
+        let dsp_pool = self.dsp_pool.clone();
+        let dsp_stats = Arc::new(SyncMutex::new(DspStats::default()));
+        let listener_dsp_stats = dsp_stats.clone();
+        let response_task = self.response_task.clone();
+        let task = tokio::spawn(async move {
+            if restarts > 0 {
+                // Whatever the crashed listener was doing is lost, don't leave
+                // the assistant waiting on it.
+                assistant.lock().await.try_clear_attention(ssrc);
+            }
+            listener::listener_loop(rx, assistant.clone(), speech_to_text, ssrc, dsp_pool, listener_dsp_stats, response_task).await;
+            // The listener was removed, its user can't let go of the assistant anymore.
+            assistant.lock().await.try_clear_attention(ssrc);
+        });
+
+        self.router.insert(ssrc, tx.clone());
+        self.listeners.insert(
+            ssrc,
+            ListenerHandle {
+                user_id: user_id,
+                tx: tx,
+                task: task,
+                restarts: restarts,
+                dsp_stats: dsp_stats,
+            },
+        );
+    }
+
+    // Returns whether the SSRC has a running listener afterwards.
+    pub fn restart_if_crashed(&mut self, ssrc: u32) -> bool {
+        let (user_id, restarts) = match self.listeners.get(&ssrc) {
+            Some(listener) if !listener.task.is_finished() => return true,
+            Some(listener) => (listener.user_id, listener.restarts + 1),
+            None => return false,
+        };
+
+        self.listeners.remove(&ssrc);
+        if restarts > MAX_RESTARTS {
+            self.router.remove(ssrc);
+            println!("Listener for SSRC {} keeps crashing, giving up on it", ssrc);
+            // A crashed listener never got to let go of the assistant itself.
+            let assistant = self.assistant.clone();
+            tokio::spawn(async move {
+                assistant.lock().await.try_clear_attention(ssrc);
+            });
+            return false;
+        }
+
+        println!("Listener for SSRC {} crashed, restarting it ({}/{})", ssrc, restarts, MAX_RESTARTS);
+        self.spawn(ssrc, user_id, restarts);
+        true
+    }
+
+    fn remove(&mut self, ssrc: u32) {
+        if let Some(listener) = self.listeners.remove(&ssrc) {
+            self.router.remove(ssrc);
+            let _ = listener.tx.try_send(ListenerEvent::Disconnect);
+            if let Some(user_id) = listener.user_id {
+                if self.id_to_ssrc.get(&user_id) == Some(&ssrc) {
+                    self.id_to_ssrc.remove(&user_id);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use serenity::model::id::GuildId;
+    use songbird::Songbird;
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::{
+        agent_speaker::AgentSpeaker,
+        llm::ScriptedLlmBackend,
+        mixer::{DuckSettings, DuckingMixer},
+        sound_store::SoundStore,
+        speech_to_text::SpeechToTextResult,
+        text_to_speech::{TextToSpeech, TextToSpeechResult},
+    };
+
+    struct Mute;
+
+    #[async_trait]
+    impl SpeechToText for Mute {
+        async fn transcribe(&self, _wav: Bytes) -> SpeechToTextResult {
+            Ok(String::new())
+        }
+    }
+
+    #[async_trait]
+    impl TextToSpeech for Mute {
+        async fn synthesize(&self, _text: &str) -> TextToSpeechResult {
+            simple_error::bail!("muted")
+        }
+
+        fn identity(&self) -> String {
+            "mute".into()
+        }
+    }
+
+    async fn manager() -> (ListenerManager, Arc<Mutex<DiscordAssistant>>) {
+        std::env::set_var("ASSISTANT_INSTRUCTIONS", "");
+        let speaker = AgentSpeaker::new(
+            Songbird::serenity(),
+            GuildId::new(1).into(),
+            Arc::new(Mute),
+            Arc::new(SyncMutex::new(SoundStore::new("sounds".into(), HashMap::new(), 0))),
+            DuckingMixer::new(DuckSettings {
+                level: 1.0,
+                fade_down: Duration::ZERO,
+                fade_up: Duration::ZERO,
+                hold: Duration::ZERO,
+            }),
+        );
+        let (action_tx, _) = broadcast::channel(1);
+        let assistant = Arc::new(Mutex::new(
+            DiscordAssistant::new(
+                Arc::new(ScriptedLlmBackend::new(Vec::new())),
+                Arc::new(Mute),
+                speaker,
+                GuildId::new(1),
+                action_tx,
+            )
+            .await,
+        ));
+        let manager = ListenerManager::new(assistant.clone(), Arc::new(Mute), Arc::new(DspPool::new(1, 4)));
+        (manager, assistant)
+    }
+
+    // Waits for the removed listener to let go of the assistant.
+    async fn grab_attention(assistant: &Arc<Mutex<DiscordAssistant>>, ssrc: u32) -> bool {
+        for _ in 0..100 {
+            if assistant.lock().await.try_grab_attention(ssrc) {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn leaving_respondant_lets_someone_else_talk() {
+        let (mut manager, assistant) = manager().await;
+        manager.on_speaking(1, Some(UserId(10)));
+        manager.on_speaking(2, Some(UserId(20)));
+        assert!(assistant.lock().await.try_grab_attention(1));
+        assert!(!assistant.lock().await.try_grab_attention(2));
+
+        manager.on_disconnect(UserId(10));
+        assert!(grab_attention(&assistant, 2).await);
+    }
+
+    #[tokio::test]
+    async fn respondant_moving_to_a_new_ssrc_lets_go() {
+        let (mut manager, assistant) = manager().await;
+        manager.on_speaking(1, Some(UserId(10)));
+        assert!(assistant.lock().await.try_grab_attention(1));
+
+        manager.on_speaking(3, Some(UserId(10)));
+        assert!(grab_attention(&assistant, 3).await);
+    }
+}
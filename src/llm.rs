@@ -0,0 +1,300 @@
+use std::{collections::VecDeque, env, error::Error, sync::{Arc, Mutex}};
+
+use async_openai::{
+    config::OpenAIConfig,
+    types::{
+        ChatCompletionMessageToolCall, ChatCompletionRequestMessage, ChatCompletionTool,
+        ChatCompletionToolType, CreateChatCompletionRequest, CreateChatCompletionRequestArgs,
+        FunctionCall,
+    },
+    Client,
+};
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde_json::Value;
+use simple_error::{bail, SimpleError};
+use tokio::sync::mpsc;
+
+pub enum LlmReply {
+    Message(String),
+    // One or more tools to run, in order.
+    ToolCalls(Vec<ChatCompletionMessageToolCall>),
+}
+
+pub type LlmResult = Result<LlmReply, Box<dyn Error + Send + Sync>>;
+
+#[async_trait]
+pub trait LlmBackend: Send + Sync {
+    async fn chat(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        tools: &[ChatCompletionTool],
+    ) -> LlmResult;
+
+    // Like `chat`, but also hands over the reply's text piece by piece while
+    // it is being generated. Backends that can't stream send it in one piece.
+    async fn chat_stream(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        tools: &[ChatCompletionTool],
+        text_tx: mpsc::UnboundedSender<String>,
+    ) -> LlmResult {
+        let reply = self.chat(messages, tools).await?;
+        if let LlmReply::Message(content) = &reply {
+            let _ = text_tx.send(content.clone());
+        }
+        Ok(reply)
+    }
+}
+
+// Talks to OpenAI or any server exposing an OpenAI-compatible chat API
+// (llama.cpp, vLLM, ...).
+pub struct OpenAILlmBackend {
+    client: Client<OpenAIConfig>,
+    model: String,
+}
+
+impl OpenAILlmBackend {
+    pub fn new(client: Client<OpenAIConfig>, model: String) -> Self {
+        OpenAILlmBackend {
+            client: client,
+            model: model,
+        }
+    }
+
+    fn request(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        tools: &[ChatCompletionTool],
+    ) -> Result<CreateChatCompletionRequest, Box<dyn Error + Send + Sync>> {
+        let mut request = CreateChatCompletionRequestArgs::default();
+        request.model(self.model.clone()).messages(messages.to_vec());
+        if !tools.is_empty() {
+            request.tools(tools.to_vec());
+        }
+        Ok(request.build()?)
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OpenAILlmBackend {
+    async fn chat(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        tools: &[ChatCompletionTool],
+    ) -> LlmResult {
+        let request = self.request(messages, tools)?;
+        let response = self.client.chat().create(request).await?;
+        let choice = match response.choices.into_iter().next() {
+            Some(choice) => choice,
+            None => bail!("chat completion returned no choices"),
+        };
+
+        // Not every compatible server reports a tool_calls finish reason, so
+        // go by whether any calls came back at all.
+        if let Some(tool_calls) = choice.message.tool_calls {
+            if !tool_calls.is_empty() {
+                return Ok(LlmReply::ToolCalls(tool_calls));
+            }
+        }
+
+        match choice.message.content {
+            Some(content) => Ok(LlmReply::Message(content)),
+            None => bail!("chat completion returned no content"),
+        }
+    }
+
+    async fn chat_stream(
+        &self,
+        messages: &[ChatCompletionRequestMessage],
+        tools: &[ChatCompletionTool],
+        text_tx: mpsc::UnboundedSender<String>,
+    ) -> LlmResult {
+        let request = self.request(messages, tools)?;
+        let mut stream = self.client.chat().create_stream(request).await?;
+
+        let mut content = String::new();
+        let mut tool_calls: Vec<ChatCompletionMessageToolCall> = Vec::new();
+        while let Some(response) = stream.next().await {
+            let delta = match response?.choices.into_iter().next() {
+                Some(choice) => choice.delta,
+                None => continue,
+            };
+
+            if let Some(text) = delta.content {
+                content.push_str(&text);
+                let _ = text_tx.send(text);
+            }
+
+            // Tool calls arrive in fragments, put together by their index.
+            for chunk in delta.tool_calls.unwrap_or_default() {
+                let index = chunk.index as usize;
+                while tool_calls.len() <= index {
+                    tool_calls.push(ChatCompletionMessageToolCall {
+                        id: String::new(),
+                        r#type: ChatCompletionToolType::Function,
+                        function: FunctionCall {
+                            name: String::new(),
+                            arguments: String::new(),
+                        },
+                    });
+                }
+                let tool_call = &mut tool_calls[index];
+                if let Some(id) = chunk.id {
+                    tool_call.id = id;
+                }
+                if let Some(function) = chunk.function {
+                    if let Some(name) = function.name {
+                        tool_call.function.name.push_str(&name);
+                    }
+                    if let Some(arguments) = function.arguments {
+                        tool_call.function.arguments.push_str(&arguments);
+                    }
+                }
+            }
+        }
+
+        if !tool_calls.is_empty() {
+            return Ok(LlmReply::ToolCalls(tool_calls));
+        }
+        if content.is_empty() {
+            bail!("chat completion returned no content");
+        }
+        Ok(LlmReply::Message(content))
+    }
+}
+
+// Replays canned replies in order, for running the assistant against a script
+// in integration tests. The script is a JSON array whose entries are either
+// `{"content": "..."}` or `{"tool_calls": [{"name": "...", "arguments": {...}}, ...]}`.
+pub struct ScriptedLlmBackend {
+    replies: Mutex<VecDeque<LlmReply>>,
+}
+
+impl ScriptedLlmBackend {
+    pub fn new(replies: Vec<LlmReply>) -> Self {
+        ScriptedLlmBackend {
+            replies: Mutex::new(replies.into()),
+        }
+    }
+
+    pub fn from_json(script: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let entries: Vec<Value> = serde_json::from_str(script)?;
+        let mut replies = Vec::with_capacity(entries.len());
+        for entry in entries {
+            if let Some(content) = entry.get("content").and_then(Value::as_str) {
+                replies.push(LlmReply::Message(content.into()));
+            } else if let Some(Value::Array(calls)) = entry.get("tool_calls") {
+                let mut tool_calls = Vec::with_capacity(calls.len());
+                for call in calls {
+                    let name = call
+                        .get("name")
+                        .and_then(Value::as_str)
+                        .ok_or(SimpleError::new("scripted tool call is missing a name"))?;
+                    let arguments = match call.get("arguments") {
+                        Some(Value::String(arguments)) => arguments.clone(),
+                        Some(arguments) => arguments.to_string(),
+                        None => "{}".into(),
+                    };
+                    tool_calls.push(ChatCompletionMessageToolCall {
+                        id: format!("call_{}_{}", replies.len(), tool_calls.len()),
+                        r#type: ChatCompletionToolType::Function,
+                        function: FunctionCall {
+                            name: name.into(),
+                            arguments: arguments,
+                        },
+                    });
+                }
+                replies.push(LlmReply::ToolCalls(tool_calls));
+            } else {
+                bail!("unrecognized script entry: {}", entry);
+            }
+        }
+        Ok(ScriptedLlmBackend::new(replies))
+    }
+}
+
+#[async_trait]
+impl LlmBackend for ScriptedLlmBackend {
+    async fn chat(
+        &self,
+        _messages: &[ChatCompletionRequestMessage],
+        _tools: &[ChatCompletionTool],
+    ) -> LlmResult {
+        match self.replies.lock().unwrap().pop_front() {
+            Some(reply) => Ok(reply),
+            None => bail!("scripted llm backend ran out of replies"),
+        }
+    }
+}
+
+// LLM_BACKEND selects the engine: "openai" (default) or "scripted".
+// LLM_BASE_URL and LLM_API_KEY point the openai backend at a compatible server.
+pub fn init_llm_backend() -> Arc<dyn LlmBackend> {
+    let backend = env::var("LLM_BACKEND").unwrap_or("openai".into());
+    match backend.as_str() {
+        "openai" => {
+            let mut config = OpenAIConfig::new();
+            if let Ok(base_url) = env::var("LLM_BASE_URL") {
+                config = config.with_api_base(base_url);
+            }
+            if let Ok(api_key) = env::var("LLM_API_KEY") {
+                config = config.with_api_key(api_key);
+            }
+            let model = env::var("ASSISTANT_MODEL").expect("Couldn't find env ASSISTANT_MODEL!");
+            Arc::new(OpenAILlmBackend::new(Client::with_config(config), model))
+        }
+        "scripted" => {
+            let path = env::var("LLM_SCRIPT").expect("Couldn't find env LLM_SCRIPT!");
+            let script = std::fs::read_to_string(&path).expect("Couldn't read LLM_SCRIPT!");
+            Arc::new(ScriptedLlmBackend::from_json(&script).expect("Couldn't parse LLM_SCRIPT!"))
+        }
+        _ => panic!("Unknown LLM_BACKEND {}!", backend),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"[
+        {"tool_calls": [
+            {"name": "request_song", "arguments": {"title": "never gonna give you up"}},
+            {"name": "skip_song", "arguments": "{}"}
+        ]},
+        {"content": "Queued it up!"}
+    ]"#;
+
+    #[tokio::test]
+    async fn scripted_backend_replays_in_order() {
+        let llm = ScriptedLlmBackend::from_json(SCRIPT).unwrap();
+
+        match llm.chat(&[], &[]).await.unwrap() {
+            LlmReply::ToolCalls(tool_calls) => {
+                assert_eq!(tool_calls.len(), 2);
+                assert_eq!(tool_calls[0].id, "call_0_0");
+                assert_eq!(tool_calls[0].function.name, "request_song");
+                let arguments: Value = serde_json::from_str(&tool_calls[0].function.arguments).unwrap();
+                assert_eq!(arguments["title"], "never gonna give you up");
+                assert_eq!(tool_calls[1].id, "call_0_1");
+                assert_eq!(tool_calls[1].function.name, "skip_song");
+                assert_eq!(tool_calls[1].function.arguments, "{}");
+            }
+            LlmReply::Message(content) => panic!("expected tool calls, got {}", content),
+        }
+
+        match llm.chat(&[], &[]).await.unwrap() {
+            LlmReply::Message(content) => assert_eq!(content, "Queued it up!"),
+            LlmReply::ToolCalls(_) => panic!("expected a message"),
+        }
+
+        let error = llm.chat(&[], &[]).await.err().unwrap();
+        assert_eq!(error.to_string(), "scripted llm backend ran out of replies");
+    }
+
+    #[test]
+    fn scripted_backend_rejects_unknown_entries() {
+        assert!(ScriptedLlmBackend::from_json(r#"[{"speak": "hi"}]"#).is_err());
+        assert!(ScriptedLlmBackend::from_json(r#"[{"tool_calls": [{"arguments": {}}]}]"#).is_err());
+    }
+}
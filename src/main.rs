@@ -1,11 +1,27 @@
 mod assistant;
+mod audio_router;
 mod discord;
+mod dsp_pool;
 mod listener;
+mod listener_manager;
 mod sound_store;
 mod agent_speaker;
 mod resampler;
+mod sentence_splitter;
 mod actions;
 mod action_handler;
+mod guild_settings;
+mod llm;
+mod mixer;
+mod music;
+mod music_bot_profile;
+mod output_scheduler;
+mod speech_to_text;
+mod text_to_speech;
+mod tools;
+mod tts_cache;
+mod vad;
+mod wake_word;
 
 use std::{collections::HashMap, sync::Arc};
 use async_openai::Client;
@@ -20,16 +36,28 @@ async fn main() {
     let mut client = discord::init_serenity().await;
 
     let (action_tx, _) = broadcast::channel(16);
-    let sound_store = sound_store::init_sound_store().await;
+    let oai_client = Arc::new(Client::new());
+    let speech_to_text = speech_to_text::init_speech_to_text(oai_client.clone());
+    let text_to_speech = text_to_speech::init_text_to_speech(oai_client);
+    let sound_store = sound_store::init_sound_store(&text_to_speech).await;
+    tokio::spawn(sound_store::watch_sounds(sound_store.clone()));
+    let llm = llm::init_llm_backend();
+    let guild_settings = guild_settings::init_guild_settings();
+    let music_bot_profiles = music_bot_profile::init_music_bot_profiles();
     {
         // Initialize shared state.
         let mut guard: tokio::sync::RwLockWriteGuard<'_, serenity::prelude::TypeMap> = client.data.write().await;
         guard.insert::<discord::SharedState>(discord::SharedState {
-            users: HashMap::default(),
-            id_to_ssrc: HashMap::default(),
-            oai_client: Arc::new(Client::new()),
-            sound_store: std::sync::Arc::new(std::sync::Mutex::new(sound_store)),
-            action_channel_tx: action_tx.clone()
+            guilds: HashMap::default(),
+            llm: llm,
+            speech_to_text: speech_to_text,
+            text_to_speech: text_to_speech,
+            sound_store: sound_store,
+            action_channel_tx: action_tx.clone(),
+            guild_settings: Arc::new(std::sync::Mutex::new(guild_settings)),
+            music_bot_profiles: Arc::new(music_bot_profiles),
+            dsp_pool: dsp_pool::init_dsp_pool(),
+            duck_settings: mixer::init_duck_settings()
         });
     };
 
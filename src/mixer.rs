@@ -0,0 +1,139 @@
+use std::{
+    env,
+    sync::{Arc, Mutex as SyncMutex},
+    time::Duration,
+};
+
+use songbird::tracks::TrackHandle;
+use tokio::task::JoinHandle;
+
+// How often the volume is stepped during a fade.
+const FADE_STEP: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Copy, Debug)]
+pub struct DuckSettings {
+    // Volume of background tracks while the assistant talks, 1.0 is full volume.
+    pub level: f32,
+    pub fade_down: Duration,
+    pub fade_up: Duration,
+    // How long the assistant has to stay quiet before the background comes
+    // back up, so it doesn't pump between the sentences of a reply.
+    pub hold: Duration,
+}
+
+#[derive(Default)]
+struct MixerState {
+    background: Vec<TrackHandle>,
+    // Current volume of the background tracks.
+    gain: f32,
+    ducked: bool,
+    fade: Option<JoinHandle<()>>,
+}
+
+// Lowers the volume of background tracks, like music, while the assistant
+// talks over them and brings it back up afterwards. Clones share the same
+// tracks.
+#[derive(Clone)]
+pub struct DuckingMixer {
+    settings: DuckSettings,
+    state: Arc<SyncMutex<MixerState>>,
+}
+
+impl DuckingMixer {
+    pub fn new(settings: DuckSettings) -> Self {
+        DuckingMixer {
+            settings: settings,
+            state: Arc::new(SyncMutex::new(MixerState {
+                gain: 1.0,
+                ..MixerState::default()
+            })),
+        }
+    }
+
+    // Tracks added while the assistant is talking start out ducked.
+    pub fn add_background(&self, handle: TrackHandle) {
+        let mut state = self.state.lock().unwrap();
+        if handle.set_volume(state.gain).is_ok() {
+            state.background.push(handle);
+        }
+    }
+
+    pub fn speech_started(&self) {
+        self.fade_to(true);
+    }
+
+    pub fn speech_ended(&self) {
+        self.fade_to(false);
+    }
+
+    fn fade_to(&self, ducked: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.ducked == ducked {
+            return;
+        }
+        state.ducked = ducked;
+        if let Some(fade) = state.fade.take() {
+            fade.abort();
+        }
+
+        let (target, duration, delay) = if ducked {
+            (self.settings.level, self.settings.fade_down, Duration::ZERO)
+        } else {
+            (1.0, self.settings.fade_up, self.settings.hold)
+        };
+        // A fade that was cut short only has part of the way left to go.
+        let full_range = (1.0 - self.settings.level).max(f32::EPSILON);
+        let steps = (duration.as_millis() / FADE_STEP.as_millis()).max(1) as f32;
+        let step = full_range / steps;
+
+        let mixer_state = self.state.clone();
+        state.fade = Some(tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let mut interval = tokio::time::interval(FADE_STEP);
+            loop {
+                interval.tick().await;
+                let mut state = mixer_state.lock().unwrap();
+                let gain = if state.gain < target {
+                    (state.gain + step).min(target)
+                } else {
+                    (state.gain - step).max(target)
+                };
+                state.gain = gain;
+                // Finished tracks won't take a volume anymore, forget about them.
+                state.background.retain(|handle| handle.set_volume(gain).is_ok());
+                if gain == target {
+                    break;
+                }
+            }
+        }));
+    }
+}
+
+// DUCK_LEVEL is the background volume while the assistant talks, between 0
+// and 1, 0.25 by default. DUCK_FADE_DOWN_MS and DUCK_FADE_UP_MS are how long
+// lowering and restoring it take, DUCK_HOLD_MS how long to wait before
+// restoring it.
+pub fn init_duck_settings() -> DuckSettings {
+    let level: f32 = env::var("DUCK_LEVEL")
+        .unwrap_or("0.25".into())
+        .parse()
+        .expect("Couldn't parse env DUCK_LEVEL!");
+    let fade_down_ms: u64 = env::var("DUCK_FADE_DOWN_MS")
+        .unwrap_or("150".into())
+        .parse()
+        .expect("Couldn't parse env DUCK_FADE_DOWN_MS!");
+    let fade_up_ms: u64 = env::var("DUCK_FADE_UP_MS")
+        .unwrap_or("600".into())
+        .parse()
+        .expect("Couldn't parse env DUCK_FADE_UP_MS!");
+    let hold_ms: u64 = env::var("DUCK_HOLD_MS")
+        .unwrap_or("500".into())
+        .parse()
+        .expect("Couldn't parse env DUCK_HOLD_MS!");
+    DuckSettings {
+        level: level.clamp(0.0, 1.0),
+        fade_down: Duration::from_millis(fade_down_ms),
+        fade_up: Duration::from_millis(fade_up_ms),
+        hold: Duration::from_millis(hold_ms),
+    }
+}
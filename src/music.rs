@@ -0,0 +1,290 @@
+use std::{
+    env,
+    error::Error,
+    path::PathBuf,
+    process::{Command as StdCommand, Stdio},
+    sync::Arc,
+    time::Duration,
+};
+
+use rand::seq::SliceRandom;
+use simple_error::bail;
+use songbird::id::GuildId;
+use songbird::input::{ChildContainer, Input, YoutubeDl};
+use songbird::tracks::{LoopState, TrackHandle};
+use songbird::typemap::TypeMapKey;
+use songbird::{Call, Songbird};
+use tokio::{process::Command, sync::{broadcast, Mutex}};
+
+use crate::actions::{AssistantAction, MusicBotAction};
+use crate::mixer::DuckingMixer;
+
+// What a queued track was requested as, so it can be resolved again.
+struct TrackQuery;
+
+impl TypeMapKey for TrackQuery {
+    type Value = String;
+}
+
+// Plays music straight into the assistant's own voice connection using the
+// songbird track queue, instead of typing commands for another bot.
+pub struct MusicPlayer {
+    songbird: Arc<Songbird>,
+    guild_id: GuildId,
+    // Music is turned down while the assistant talks over it.
+    mixer: DuckingMixer,
+    http_client: reqwest::Client,
+    playlists_dir: PathBuf,
+    bass_boost: bool,
+}
+
+impl MusicPlayer {
+    pub fn new(songbird: Arc<Songbird>, guild_id: GuildId, mixer: DuckingMixer) -> Self {
+        let playlists_dir = env::var("MUSIC_PLAYLISTS_DIR").unwrap_or("playlists".into());
+        MusicPlayer {
+            songbird: songbird,
+            guild_id: guild_id,
+            mixer: mixer,
+            http_client: reqwest::Client::new(),
+            playlists_dir: PathBuf::from(playlists_dir),
+            bass_boost: false,
+        }
+    }
+
+    pub async fn handle(&mut self, action: MusicBotAction) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let call_lock = match self.songbird.get(self.guild_id) {
+            Some(call_lock) => call_lock,
+            None => bail!("not connected to a voice channel"),
+        };
+
+        match action {
+            MusicBotAction::Summon => {
+                // The player already lives in our voice connection, pick up where it left off.
+                let call = call_lock.lock().await;
+                call.queue().resume()?;
+            }
+            MusicBotAction::Dismiss => {
+                let call = call_lock.lock().await;
+                call.queue().stop();
+            }
+            MusicBotAction::Request(title) => {
+                let input = self.resolve(&title, Duration::ZERO, self.bass_boost).await?;
+                let mut call = call_lock.lock().await;
+                self.enqueue(&mut call, &title, input).await;
+            }
+            MusicBotAction::Skip => {
+                let call = call_lock.lock().await;
+                call.queue().skip()?;
+            }
+            MusicBotAction::Shuffle => {
+                let call = call_lock.lock().await;
+                // Leave the playing track where it is, only the upcoming ones move.
+                call.queue().modify_queue(|queue| {
+                    if queue.len() > 2 {
+                        queue.make_contiguous()[1..].shuffle(&mut rand::thread_rng());
+                    }
+                });
+            }
+            MusicBotAction::Loop => {
+                let call = call_lock.lock().await;
+                if let Some(current) = call.queue().current() {
+                    match current.get_info().await?.loops {
+                        LoopState::Finite(0) => current.enable_loop()?,
+                        _ => current.disable_loop()?,
+                    }
+                }
+            }
+            MusicBotAction::Clear => {
+                let call = call_lock.lock().await;
+                call.queue().modify_queue(|queue| {
+                    if queue.len() > 1 {
+                        for queued in queue.drain(1..) {
+                            let _ = queued.stop();
+                        }
+                    }
+                });
+            }
+            MusicBotAction::BassBoost => {
+                // Only flipped once the playing song has been switched over,
+                // so a failed toggle leaves the setting matching what's heard.
+                let bass_boost = !self.bass_boost;
+                self.reapply_bass_boost(&call_lock, bass_boost).await?;
+                self.bass_boost = bass_boost;
+                println!("Bass boost {}", if self.bass_boost { "on" } else { "off" });
+            }
+            MusicBotAction::PlayPlaylist(playlist) => {
+                let entries = self.load_playlist(&playlist).await?;
+                for entry in entries {
+                    match self.resolve(&entry, Duration::ZERO, self.bass_boost).await {
+                        Ok(input) => {
+                            let mut call = call_lock.lock().await;
+                            self.enqueue(&mut call, &entry, input).await;
+                        }
+                        Err(e) => println!("Couldn't queue {} from {}: {}", entry, playlist, e),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn enqueue(&self, call: &mut Call, query: &str, input: Input) -> TrackHandle {
+        let handle = call.enqueue_input(input).await;
+        handle.typemap().write().await.insert::<TrackQuery>(query.to_string());
+        self.mixer.add_background(handle.clone());
+        handle
+    }
+
+    // Bass boost is baked in when a song is resolved, so the playing song is
+    // resolved again and picks up from where it was. Songs already queued
+    // keep the setting they were queued with.
+    async fn reapply_bass_boost(
+        &self,
+        call_lock: &Arc<Mutex<Call>>,
+        bass_boost: bool,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let current = match call_lock.lock().await.queue().current() {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let query = match current.typemap().read().await.get::<TrackQuery>() {
+            Some(query) => query.clone(),
+            None => return Ok(()),
+        };
+        let info = current.get_info().await?;
+        let position = info.position;
+
+        let input = self.resolve(&query, position, bass_boost).await?;
+        let mut call = call_lock.lock().await;
+        let handle = self.enqueue(&mut call, &query, input).await;
+        // A looping song keeps looping.
+        match info.loops {
+            LoopState::Infinite => handle.enable_loop()?,
+            LoopState::Finite(loops) if loops > 0 => handle.loop_for(loops)?,
+            LoopState::Finite(_) => {}
+        }
+        // Move it up behind the playing song, then skip to it.
+        call.queue().modify_queue(|queue| {
+            if queue.len() > 2 {
+                if let Some(replacement) = queue.pop_back() {
+                    queue.insert(1, replacement);
+                }
+            }
+        });
+        if let Err(e) = call.queue().skip() {
+            // Don't leave the replacement to play the song a second time.
+            let _ = handle.stop();
+            return Err(e.into());
+        }
+        if !bass_boost {
+            let _ = handle.seek(position);
+        }
+        Ok(())
+    }
+
+    // Boosted songs start at `start`, others have to be seeked once queued.
+    async fn resolve(
+        &self,
+        query: &str,
+        start: Duration,
+        bass_boost: bool,
+    ) -> Result<Input, Box<dyn Error + Send + Sync>> {
+        let is_url = query.starts_with("http://") || query.starts_with("https://");
+
+        if !bass_boost {
+            let source = if is_url {
+                YoutubeDl::new(self.http_client.clone(), query.to_string())
+            } else {
+                YoutubeDl::new_search(self.http_client.clone(), query.to_string())
+            };
+            return Ok(source.into());
+        }
+
+        // Songbird has no filters of its own, so boosted tracks are piped
+        // through ffmpeg's bass filter.
+        let target = if is_url { query.to_string() } else { format!("ytsearch1:{}", query) };
+        let output = Command::new("yt-dlp")
+            .args(["-f", "bestaudio", "--get-url", "--no-playlist", target.as_str()])
+            .output()
+            .await?;
+        let stream_url = String::from_utf8_lossy(&output.stdout).lines().next().unwrap_or("").to_string();
+        if !output.status.success() || stream_url.is_empty() {
+            bail!("yt-dlp couldn't find {}", query);
+        }
+
+        let start = format!("{:.3}", start.as_secs_f64());
+        let ffmpeg = StdCommand::new("ffmpeg")
+            .args(["-reconnect", "1", "-reconnect_streamed", "1", "-ss", start.as_str(), "-i", stream_url.as_str()])
+            .args(["-af", "bass=g=10", "-f", "mp3", "-loglevel", "error", "pipe:1"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        Ok(ChildContainer::from(ffmpeg).into())
+    }
+
+    // Playlists are text files in MUSIC_PLAYLISTS_DIR, one song title or url
+    // per line, matched by file name.
+    async fn load_playlist(&self, playlist: &str) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+        let wanted = playlist.trim().to_lowercase();
+        let mut dir = tokio::fs::read_dir(&self.playlists_dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            let stem = match path.file_stem() {
+                Some(stem) => stem.to_string_lossy().to_lowercase(),
+                None => continue,
+            };
+            if stem == wanted {
+                let contents = tokio::fs::read_to_string(&path).await?;
+                return Ok(contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(String::from)
+                    .collect());
+            }
+        }
+        bail!("no playlist named {}", playlist);
+    }
+}
+
+// MUSIC_BACKEND selects who plays music: "external" (default) drives another
+// bot through each guild's music command channel, "native" plays it ourselves.
+pub fn use_native_player() -> bool {
+    match env::var("MUSIC_BACKEND") {
+        Ok(backend) => match backend.as_str() {
+            "native" => true,
+            "external" => false,
+            _ => panic!("Unknown MUSIC_BACKEND {}!", backend),
+        },
+        Err(_) => false,
+    }
+}
+
+pub async fn music_player_loop(
+    mut player: MusicPlayer,
+    mut action_rx: broadcast::Receiver<AssistantAction>,
+) {
+    loop {
+        let action = match action_rx.recv().await {
+            Ok(action) => action,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                println!("Music player fell behind, skipped {} actions", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        match action {
+            AssistantAction::MusicBot(guild_id, music_bot_action) => {
+                // Every guild's player hears every action, only act on our own.
+                if GuildId::from(guild_id) != player.guild_id {
+                    continue;
+                }
+                if let Err(e) = player.handle(music_bot_action.clone()).await {
+                    println!("Music player couldn't {:?}: {}", music_bot_action, e);
+                }
+            }
+        }
+    }
+}
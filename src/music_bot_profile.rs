@@ -0,0 +1,162 @@
+use std::{collections::HashMap, env, error::Error};
+
+use serde::Deserialize;
+
+use crate::actions::MusicBotAction;
+
+// Command templates for each `MusicBotAction`, a `{}` is replaced by the song
+// title or playlist name. Actions the bot doesn't support are left out.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CommandTemplates {
+    pub summon: Option<String>,
+    pub dismiss: Option<String>,
+    pub request: Option<String>,
+    pub skip: Option<String>,
+    pub shuffle: Option<String>,
+    #[serde(rename = "loop")]
+    pub loop_track: Option<String>,
+    pub clear: Option<String>,
+    pub bass_boost: Option<String>,
+    pub play_playlist: Option<String>,
+}
+
+// How commands are addressed to a music bot. Discord doesn't run slash
+// commands sent by other bots, so a "slash style" bot is one that also reads
+// text commands starting with `/`, i.e. a `/` prefix.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MusicBotProfile {
+    #[serde(default)]
+    pub prefix: String,
+    // Mention the bot with this user id instead of using a prefix.
+    #[serde(default)]
+    pub mention: Option<u64>,
+    pub commands: CommandTemplates,
+}
+
+impl MusicBotProfile {
+    pub fn render(&self, action: &MusicBotAction) -> Option<String> {
+        let (template, argument) = match action {
+            MusicBotAction::Summon => (&self.commands.summon, None),
+            MusicBotAction::Dismiss => (&self.commands.dismiss, None),
+            MusicBotAction::Request(title) => (&self.commands.request, Some(title)),
+            MusicBotAction::Skip => (&self.commands.skip, None),
+            MusicBotAction::Shuffle => (&self.commands.shuffle, None),
+            MusicBotAction::Loop => (&self.commands.loop_track, None),
+            MusicBotAction::Clear => (&self.commands.clear, None),
+            MusicBotAction::BassBoost => (&self.commands.bass_boost, None),
+            MusicBotAction::PlayPlaylist(playlist) => (&self.commands.play_playlist, Some(playlist)),
+        };
+
+        let mut command = template.clone()?;
+        if let Some(argument) = argument {
+            command = command.replace("{}", argument);
+        }
+
+        match self.mention {
+            Some(user_id) => Some(format!("<@{}> {}", user_id, command)),
+            None => Some(format!("{}{}", self.prefix, command)),
+        }
+    }
+}
+
+fn template(command: &str) -> Option<String> {
+    Some(command.into())
+}
+
+// The `=` prefixed commands the assistant was originally written against.
+fn default_profile() -> MusicBotProfile {
+    MusicBotProfile {
+        prefix: "=".into(),
+        mention: None,
+        commands: CommandTemplates {
+            summon: template("join"),
+            dismiss: template("leave"),
+            request: template("p {}"),
+            skip: template("skip"),
+            shuffle: template("shuffle"),
+            loop_track: template("loop"),
+            clear: template("clear"),
+            bass_boost: template("bb"),
+            play_playlist: template("playlist play {}"),
+        },
+    }
+}
+
+// Long form `!` prefixed commands, common to many music bots.
+fn bang_profile() -> MusicBotProfile {
+    MusicBotProfile {
+        prefix: "!".into(),
+        mention: None,
+        commands: CommandTemplates {
+            summon: template("join"),
+            dismiss: template("leave"),
+            request: template("play {}"),
+            skip: template("skip"),
+            shuffle: template("shuffle"),
+            loop_track: template("loop"),
+            clear: template("clear"),
+            bass_boost: template("bassboost"),
+            play_playlist: template("playlist {}"),
+        },
+    }
+}
+
+pub struct MusicBotProfiles {
+    profiles: HashMap<String, MusicBotProfile>,
+    // Used by guilds that haven't picked a profile.
+    default_name: String,
+}
+
+impl MusicBotProfiles {
+    pub fn builtin() -> Self {
+        let mut profiles = HashMap::new();
+        profiles.insert("default".into(), default_profile());
+        profiles.insert("bang".into(), bang_profile());
+        MusicBotProfiles {
+            profiles: profiles,
+            default_name: "default".into(),
+        }
+    }
+
+    // Adds custom profiles from a JSON object of profile name to profile,
+    // overriding built-in ones with the same name.
+    pub fn extend_from_json(&mut self, json: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let custom: HashMap<String, MusicBotProfile> = serde_json::from_str(json)?;
+        self.profiles.extend(custom);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&MusicBotProfile> {
+        self.profiles.get(name)
+    }
+
+    // The named profile, or the default one when no name is given.
+    pub fn get_or_default(&self, name: Option<&str>) -> Option<&MusicBotProfile> {
+        self.get(name.unwrap_or(&self.default_name))
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+}
+
+// MUSIC_BOT_PROFILES optionally points at a JSON file of custom profiles,
+// MUSIC_BOT_PROFILE names the profile guilds use by default, "default" if unset.
+pub fn init_music_bot_profiles() -> MusicBotProfiles {
+    let mut profiles = MusicBotProfiles::builtin();
+    if let Ok(path) = env::var("MUSIC_BOT_PROFILES") {
+        let json = std::fs::read_to_string(&path).expect("Couldn't read MUSIC_BOT_PROFILES!");
+        profiles
+            .extend_from_json(&json)
+            .expect("Couldn't parse MUSIC_BOT_PROFILES!");
+    }
+    if let Ok(name) = env::var("MUSIC_BOT_PROFILE") {
+        if profiles.get(&name).is_none() {
+            panic!("Unknown MUSIC_BOT_PROFILE {}!", name);
+        }
+        profiles.default_name = name;
+    }
+    profiles
+}
@@ -0,0 +1,329 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use songbird::events::EventData;
+use songbird::id::GuildId;
+use songbird::input::Input;
+use songbird::tracks::{LoopState, PlayMode, Track, TrackHandle};
+use songbird::{Event, EventContext, EventHandler as VoiceEventHandler, Songbird, TrackEvent};
+use tokio::sync::oneshot;
+
+use crate::mixer::DuckingMixer;
+
+// What an item is, and so what it has to wait for. Higher priorities are
+// played first, items of the same priority in the order they were queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OutputPriority {
+    // Anything that isn't a reply to the user, e.g. a notice.
+    Announcement,
+    // The assistant's replies.
+    Response,
+    // Short cues, e.g. the acknowledgement after the wake word.
+    Earcon,
+}
+
+// The lowest priority that cuts a looping item short, anything below that
+// waits its turn like it would behind anything else.
+const PREEMPTS_LOOPING: OutputPriority = OutputPriority::Response;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlaybackOutcome {
+    Finished,
+    Cancelled,
+    Failed,
+}
+
+struct OutputItem {
+    input: Input,
+    priority: OutputPriority,
+    looping: bool,
+    done: oneshot::Sender<PlaybackOutcome>,
+}
+
+struct Playing {
+    id: u64,
+    // Unset while the track is being started.
+    handle: Option<TrackHandle>,
+    looping: bool,
+    done: oneshot::Sender<PlaybackOutcome>,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    pending: VecDeque<OutputItem>,
+    playing: Option<Playing>,
+    next_id: u64,
+}
+
+impl SchedulerState {
+    // Queues `item` behind everything of the same or a higher priority,
+    // returns whether it should cut off the looping item that's playing.
+    fn push(&mut self, item: OutputItem) -> bool {
+        let preempts = item.priority >= PREEMPTS_LOOPING;
+        let position = self
+            .pending
+            .iter()
+            .position(|pending| pending.priority < item.priority)
+            .unwrap_or(self.pending.len());
+        self.pending.insert(position, item);
+        preempts && matches!(self.playing, Some(Playing { looping: true, .. }))
+    }
+}
+
+// Plays the assistant's output one item at a time. Nothing is cut off to
+// make room for something else except looping items, like the waiting ping,
+// which only fill the time until there is something to play. Background
+// tracks are ducked for as long as anything is playing.
+#[derive(Clone)]
+pub struct OutputScheduler {
+    songbird: Arc<Songbird>,
+    guild_id: GuildId,
+    mixer: DuckingMixer,
+    state: Arc<SyncMutex<SchedulerState>>,
+}
+
+impl OutputScheduler {
+    pub fn new(songbird: Arc<Songbird>, guild_id: GuildId, mixer: DuckingMixer) -> Self {
+        OutputScheduler {
+            songbird: songbird,
+            guild_id: guild_id,
+            mixer: mixer,
+            state: Arc::new(SyncMutex::new(SchedulerState::default())),
+        }
+    }
+
+    // Queues `input`, the receiver reports how its playback went.
+    pub async fn enqueue(
+        &self,
+        input: Input,
+        priority: OutputPriority,
+        looping: bool,
+    ) -> oneshot::Receiver<PlaybackOutcome> {
+        let (done_tx, done_rx) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap();
+            let preempts = state.push(OutputItem {
+                input: input,
+                priority: priority,
+                looping: looping,
+                done: done_tx,
+            });
+
+            if let Some(Playing { handle: Some(handle), .. }) = &state.playing {
+                if preempts {
+                    // Ends in `TrackEnded`, which moves on to the next item.
+                    let _ = handle.stop();
+                }
+            }
+        }
+
+        self.advance().await;
+        done_rx
+    }
+
+    // Stops what is playing and drops everything still queued.
+    pub fn cancel_all(&self) {
+        let mut state = self.state.lock().unwrap();
+        for item in state.pending.drain(..) {
+            let _ = item.done.send(PlaybackOutcome::Cancelled);
+        }
+        if let Some(playing) = state.playing.take() {
+            if let Some(handle) = playing.handle {
+                let _ = handle.stop();
+            }
+            let _ = playing.done.send(PlaybackOutcome::Cancelled);
+        }
+        self.mixer.speech_ended();
+    }
+
+    // Stops and drops looping items, leaving everything else to play out.
+    pub fn cancel_looping(&self) {
+        let mut state = self.state.lock().unwrap();
+        let pending = std::mem::take(&mut state.pending);
+        for item in pending {
+            if item.looping {
+                let _ = item.done.send(PlaybackOutcome::Cancelled);
+            } else {
+                state.pending.push_back(item);
+            }
+        }
+        if let Some(Playing { handle: Some(handle), looping: true, .. }) = &state.playing {
+            let _ = handle.stop();
+        }
+    }
+
+    // Whether everything queued has been played, looping items aside.
+    pub fn is_idle(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        let playing_idle = match &state.playing {
+            Some(playing) => playing.looping,
+            None => true,
+        };
+        playing_idle && state.pending.iter().all(|item| item.looping)
+    }
+
+    // Starts the next item unless something is already playing.
+    async fn advance(&self) {
+        let (id, track) = {
+            let mut state = self.state.lock().unwrap();
+            if state.playing.is_some() {
+                return;
+            }
+            let item = match state.pending.pop_front() {
+                Some(item) => item,
+                None => return,
+            };
+            let id = state.next_id;
+            state.next_id += 1;
+            let mut track = if item.looping {
+                Track::from(item.input).loops(LoopState::Infinite)
+            } else {
+                Track::from(item.input)
+            };
+            // Watched from the start, a track that ends unnoticed would leave
+            // the scheduler busy for good.
+            for event in [TrackEvent::End, TrackEvent::Error] {
+                let ended = TrackEnded {
+                    scheduler: self.clone(),
+                    id: id,
+                };
+                track.events.add_event(EventData::new(Event::Track(event), ended), Duration::ZERO);
+            }
+            state.playing = Some(Playing {
+                id: id,
+                handle: None,
+                looping: item.looping,
+                done: item.done,
+            });
+            (id, track)
+        };
+
+        let call_lock = match self.songbird.get(self.guild_id) {
+            Some(call_lock) => call_lock,
+            None => {
+                // Left the channel, nothing can be played anymore.
+                self.cancel_all();
+                return;
+            }
+        };
+
+        self.mixer.speech_started();
+        let handle = call_lock.lock().await.play(track);
+
+        let mut state = self.state.lock().unwrap();
+        let preempted = state.pending.iter().any(|item| item.priority >= PREEMPTS_LOOPING);
+        match state.playing.as_mut() {
+            Some(playing) if playing.id == id => {
+                // Something was queued while a looping item was starting.
+                if playing.looping && preempted {
+                    let _ = handle.stop();
+                }
+                playing.handle = Some(handle);
+            }
+            // Cancelled while it was starting.
+            _ => {
+                let _ = handle.stop();
+            }
+        }
+    }
+
+    fn finish(&self, id: u64, outcome: PlaybackOutcome) {
+        let mut state = self.state.lock().unwrap();
+        let is_current = match &state.playing {
+            Some(playing) => playing.id == id,
+            None => false,
+        };
+        if is_current {
+            let playing = state.playing.take().unwrap();
+            // Looping items never end on their own, they were cut short.
+            let outcome = if playing.looping && outcome == PlaybackOutcome::Finished {
+                PlaybackOutcome::Cancelled
+            } else {
+                outcome
+            };
+            let _ = playing.done.send(outcome);
+        }
+    }
+
+    fn end_if_idle(&self) {
+        if self.state.lock().unwrap().playing.is_none() {
+            self.mixer.speech_ended();
+        }
+    }
+}
+
+struct TrackEnded {
+    scheduler: OutputScheduler,
+    id: u64,
+}
+
+#[async_trait]
+impl VoiceEventHandler for TrackEnded {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        let failed = match ctx {
+            EventContext::Track(tracks) => tracks
+                .iter()
+                .any(|(state, _)| matches!(state.playing, PlayMode::Errored(_))),
+            _ => false,
+        };
+        let outcome = if failed { PlaybackOutcome::Failed } else { PlaybackOutcome::Finished };
+        self.scheduler.finish(self.id, outcome);
+        self.scheduler.advance().await;
+        self.scheduler.end_if_idle();
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn item(priority: OutputPriority) -> OutputItem {
+        let (done_tx, _) = oneshot::channel();
+        OutputItem {
+            input: Input::from(Bytes::new()),
+            priority: priority,
+            looping: false,
+            done: done_tx,
+        }
+    }
+
+    fn pinging() -> SchedulerState {
+        let (done_tx, _) = oneshot::channel();
+        SchedulerState {
+            playing: Some(Playing {
+                id: 0,
+                handle: None,
+                looping: true,
+                done: done_tx,
+            }),
+            ..SchedulerState::default()
+        }
+    }
+
+    #[test]
+    fn announcement_waits_behind_the_ping() {
+        let mut state = pinging();
+        assert!(!state.push(item(OutputPriority::Announcement)));
+        assert_eq!(state.pending.len(), 1);
+    }
+
+    #[test]
+    fn reply_cuts_off_the_ping_and_goes_first() {
+        let mut state = pinging();
+        state.push(item(OutputPriority::Announcement));
+        assert!(state.push(item(OutputPriority::Response)));
+        let order: Vec<OutputPriority> = state.pending.iter().map(|item| item.priority).collect();
+        assert_eq!(order, vec![OutputPriority::Response, OutputPriority::Announcement]);
+    }
+
+    #[test]
+    fn nothing_is_cut_off_but_looping_items() {
+        let mut state = pinging();
+        state.playing.as_mut().unwrap().looping = false;
+        assert!(!state.push(item(OutputPriority::Earcon)));
+    }
+}
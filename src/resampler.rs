@@ -1,22 +1,156 @@
-use std::{collections::VecDeque, time::Duration};
+use std::{collections::VecDeque, env};
 
 use rubato::{SincInterpolationParameters, SincInterpolationType, WindowFunction, SincFixedOut, Resampler as RubatoResampler};
-use tokio::{sync::mpsc, time::timeout};
+use songbird::driver::Channels;
+use tokio::sync::mpsc;
+
+// Rate songbird decodes voice packets at.
+pub const DECODE_SAMPLE_RATE: u32 = 48_000;
+// Discord's Opus RTP timestamps always count at 48kHz, whatever the decode rate.
+const RTP_CLOCK_RATE: u32 = 48_000;
+// Songbird reports every user once per 20ms tick.
+const TICK_RTP_SAMPLES: u32 = RTP_CLOCK_RATE / 50;
+// Larger jumps in the RTP timestamp are treated as the stream restarting
+// rather than as lost audio.
+const MAX_GAP_RTP_SAMPLES: u32 = RTP_CLOCK_RATE * 2;
+
+// Where a packet sits in the user's RTP stream.
+#[derive(Clone, Copy, Debug)]
+pub struct PacketTiming {
+    pub sequence: u16,
+    pub timestamp: u32,
+}
 
 pub enum ListenerEvent {
-    AudioPacket(Vec<i16>),
+    // Decoded audio, with its timing when songbird received a real packet
+    // rather than concealing a lost one.
+    AudioPacket(Vec<i16>, Option<PacketTiming>),
+    // A tick passed without the user speaking.
+    Silence,
     Disconnect
 }
 
-pub struct Resampler {
+// VOICE_DECODE_CHANNELS picks how songbird decodes voice, "mono" (default)
+// halves the decode and resample work since the engines only hear mono anyway.
+pub fn decode_channels() -> Channels {
+    match env::var("VOICE_DECODE_CHANNELS") {
+        Ok(channels) => match channels.as_str() {
+            "mono" => Channels::Mono,
+            "stereo" => Channels::Stereo,
+            _ => panic!("Unknown VOICE_DECODE_CHANNELS {}!", channels),
+        },
+        Err(_) => Channels::Mono
+    }
+}
+
+pub fn channel_count(channels: Channels) -> usize {
+    match channels {
+        Channels::Mono => 1,
+        Channels::Stereo => 2,
+    }
+}
+
+// Averages interleaved samples down to a single channel, scaled to [-1, 1].
+pub fn downmix(interleaved: &[i16], channels: usize) -> Vec<f64> {
+    interleaved
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().map(|&sample| sample as f64 / 32768.0).sum::<f64>() / channels as f64)
+        .collect()
+}
+
+// Buffers the packets from the voice driver, downmixed to mono, into the
+// blocks of frames the resampler asks for. Time the user spends quiet or
+// packets lost on the way are filled with exactly as much silence as is
+// missing, so the engines downstream keep real time.
+pub struct PacketReader {
     rx: mpsc::Receiver<ListenerEvent>,
-    buf: VecDeque<i16>,
+    buf: VecDeque<f64>,
+    sample_rate: u32,
     channels: usize,
+    last_sequence: Option<u16>,
+    // RTP timestamp the next packet should carry if nothing went missing.
+    next_timestamp: Option<u32>,
+    // Silence already filled in since the last packet, in RTP samples.
+    silence_filled: u32
+}
+
+impl PacketReader {
+    pub fn new(rx: mpsc::Receiver<ListenerEvent>, sample_rate: u32, channels: usize) -> Self {
+        Self {
+            rx: rx,
+            buf: VecDeque::new(),
+            sample_rate: sample_rate,
+            channels: channels,
+            last_sequence: None,
+            next_timestamp: None,
+            silence_filled: 0
+        }
+    }
+
+    pub async fn read_frames(&mut self, frame_count: usize) -> Option<Vec<f64>> {
+        while self.buf.len() < frame_count {
+            match self.rx.recv().await {
+                Some(ListenerEvent::AudioPacket(data, timing)) => {
+                    self.push_packet(downmix(&data, self.channels), timing);
+                },
+                Some(ListenerEvent::Silence) => {
+                    self.push_silence(TICK_RTP_SAMPLES);
+                    self.silence_filled = self.silence_filled.saturating_add(TICK_RTP_SAMPLES);
+                },
+                // The guild was torn down when the sender is gone.
+                Some(ListenerEvent::Disconnect) | None => {
+                    return None;
+                }
+            }
+        }
+
+        Some(self.buf.drain(..frame_count).collect())
+    }
+
+    fn push_packet(&mut self, samples: Vec<f64>, timing: Option<PacketTiming>) {
+        let duration = (samples.len() as u64 * RTP_CLOCK_RATE as u64 / self.sample_rate as u64) as u32;
+
+        if let Some(timing) = timing {
+            if self.last_sequence == Some(timing.sequence) {
+                // Duplicate, already heard.
+                return;
+            }
+
+            if let Some(next_timestamp) = self.next_timestamp {
+                let gap = timing.timestamp.wrapping_sub(next_timestamp) as i32;
+                if gap < 0 && gap > -(MAX_GAP_RTP_SAMPLES as i32) {
+                    // Arrived after audio that followed it, too late to place.
+                    return;
+                }
+                if gap > 0 && gap as u32 <= MAX_GAP_RTP_SAMPLES {
+                    self.push_silence((gap as u32).saturating_sub(self.silence_filled));
+                }
+            }
+
+            self.last_sequence = Some(timing.sequence);
+            self.next_timestamp = Some(timing.timestamp.wrapping_add(duration));
+        } else if let Some(next_timestamp) = self.next_timestamp {
+            // Concealment audio stands in for the packet that should have been there.
+            self.next_timestamp = Some(next_timestamp.wrapping_add(duration));
+        }
+
+        self.silence_filled = 0;
+        self.buf.extend(samples);
+    }
+
+    fn push_silence(&mut self, rtp_samples: u32) {
+        let sample_count = rtp_samples as u64 * self.sample_rate as u64 / RTP_CLOCK_RATE as u64;
+        self.buf.extend(std::iter::repeat(0.0).take(sample_count as usize));
+    }
+}
+
+// Converts mono blocks read by a `PacketReader` to frames at the engines' sample rate.
+pub struct Resampler {
     resampler: SincFixedOut<f64>
 }
 
 impl Resampler {
-    pub fn new(rx: mpsc::Receiver<ListenerEvent>, sample_rate: f64, frame_length: usize, channels: usize) -> Self {
+    pub fn new(input_rate: f64, output_rate: f64, frame_length: usize) -> Self {
         let resampler_params = SincInterpolationParameters {
             sinc_len: 256,
             f_cutoff: 0.95,
@@ -24,80 +158,181 @@ impl Resampler {
             oversampling_factor: 256,
             window: WindowFunction::BlackmanHarris2,
         };
-    
+
         let resampler = SincFixedOut::<f64>::new(
-            sample_rate / 48_000 as f64,
+            output_rate / input_rate,
             2.0,
             resampler_params,
             frame_length,
-            2,
+            1,
         ).unwrap();
 
         Self {
-            rx: rx,
-            buf: VecDeque::with_capacity(frame_length * 2),
-            channels: channels,
             resampler: resampler
         }
     }
 
-    async fn read_frames(&mut self) -> Option<Vec<Vec<f64>>> {
-        let frame_count = self.resampler.input_frames_next(); 
+    // Input frames needed for the next output frame.
+    pub fn input_frames_next(&self) -> usize {
+        self.resampler.input_frames_next()
+    }
 
-        let mut out = Vec::with_capacity(self.channels);
-        for _ in 0..self.channels {
-            out.push(Vec::with_capacity(frame_count));
-        }
-    
-        while self.buf.len() < frame_count * 2 {
-            match timeout(Duration::from_millis(100), self.rx.recv()).await {
-                Ok(event) => {
-                    match event.unwrap() {
-                        ListenerEvent::AudioPacket(data) => {
-                            self.buf.extend(data);
-                        },
-                        ListenerEvent::Disconnect => {
-                            return None;
-                        }
-                    }
-                },
-                Err(_elapsed) => {
-                    let sample_count = (100 * 48) as usize;
-                    self.buf.extend(vec![0; sample_count]);
-                }
-            }
-        }
-        
-        for _ in 0..frame_count {
-            let l = self.buf.pop_front().unwrap() as f64 / 32768.0;
-            let r = self.buf.pop_front().unwrap() as f64 / 32768.0;
-            out[0].push(l.clamp(-1.0, 1.0));
-            out[1].push(r.clamp(-1.0, 1.0));
-        }
-    
-        Some(out)
+    pub fn resample(&mut self, input: &[f64], out_frame: &mut Vec<i16>) {
+        let resampled_frame = self.resampler.process(&[input], None).unwrap();
+
+        out_frame.clear();
+        out_frame.extend(resampled_frame[0].iter().map(|v| (v * 32768.0).clamp(-32768.0, 32767.0) as i16));
     }
+}
 
-    pub async fn read_frames_resample(&mut self, out_frame: &mut Vec<i16>) -> bool {
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let frames = self.read_frames().await;
+    const FRAME_LENGTH: usize = 512;
 
-        // TODO none check
-        if frames.is_none() {
-            return false;
+    fn sine(frequency: f64, sample_rate: u32, amplitude: f64, start: usize, count: usize) -> Vec<f64> {
+        (start..start + count)
+            .map(|i| amplitude * (2.0 * std::f64::consts::PI * frequency * i as f64 / sample_rate as f64).sin())
+            .collect()
+    }
+
+    // Resamples a second of a sine wave, returning the output after the
+    // filter has settled.
+    fn resample_sine(frequency: f64, input_rate: u32, output_rate: u32, amplitude: f64) -> Vec<i16> {
+        let mut resampler = Resampler::new(input_rate as f64, output_rate as f64, FRAME_LENGTH);
+        let mut output = Vec::new();
+        let mut frame = Vec::new();
+        let mut consumed = 0;
+        while consumed < input_rate as usize {
+            let frame_count = resampler.input_frames_next();
+            resampler.resample(&sine(frequency, input_rate, amplitude, consumed, frame_count), &mut frame);
+            assert_eq!(frame.len(), FRAME_LENGTH);
+            output.extend_from_slice(&frame);
+            consumed += frame_count;
         }
+        output.split_off(FRAME_LENGTH * 2)
+    }
+
+    fn peak(samples: &[i16]) -> f64 {
+        samples.iter().map(|&sample| (sample as f64 / 32768.0).abs()).fold(0.0, f64::max)
+    }
 
-        let resampled_frame = self.resampler.process(&frames.unwrap(), None).unwrap();
+    // Estimates the frequency of a sine wave from its rising zero crossings.
+    fn frequency(samples: &[i16], sample_rate: u32) -> f64 {
+        let crossings: Vec<usize> = (1..samples.len())
+            .filter(|&i| samples[i - 1] < 0 && samples[i] >= 0)
+            .collect();
+        let cycles = (crossings.len() - 1) as f64;
+        let span = (crossings[crossings.len() - 1] - crossings[0]) as f64;
+        cycles * sample_rate as f64 / span
+    }
 
-        // Stereo to mono
-        out_frame.clear();
-        for i in 0..resampled_frame[0].len() {
-            let l = resampled_frame[0][i];
-            let r = resampled_frame[1][i];
-            let v = (l + r) / 2.0;
-            out_frame.push((v * 32768.0).clamp(-32768.0, 32768.0) as i16);
+    #[test]
+    fn downsamples_48khz_to_16khz() {
+        let output = resample_sine(440.0, 48_000, 16_000, 0.5);
+        assert!((frequency(&output, 16_000) - 440.0).abs() < 2.0);
+        assert!((peak(&output) - 0.5).abs() < 0.02);
+    }
+
+    #[test]
+    fn downsamples_44_1khz_to_16khz() {
+        let output = resample_sine(1000.0, 44_100, 16_000, 0.25);
+        assert!((frequency(&output, 16_000) - 1000.0).abs() < 2.0);
+        assert!((peak(&output) - 0.25).abs() < 0.02);
+    }
+
+    #[test]
+    fn upsamples_8khz_to_16khz() {
+        let output = resample_sine(300.0, 8_000, 16_000, 0.5);
+        assert!((frequency(&output, 16_000) - 300.0).abs() < 2.0);
+        assert!((peak(&output) - 0.5).abs() < 0.02);
+    }
+
+    #[test]
+    fn downmix_averages_channels() {
+        assert_eq!(downmix(&[16384, 0, -16384, -16384], 2), vec![0.25, -0.5]);
+        assert_eq!(downmix(&[16384, -16384], 1), vec![0.5, -0.5]);
+        // Opposite channels cancel out.
+        assert_eq!(downmix(&[8192, -8192, 0, 0], 2), vec![0.0, 0.0]);
+    }
+
+    fn packet(samples: usize, value: i16, sequence: u16, timestamp: u32) -> ListenerEvent {
+        ListenerEvent::AudioPacket(vec![value; samples], Some(PacketTiming { sequence: sequence, timestamp: timestamp }))
+    }
+
+    #[tokio::test]
+    async fn reads_stereo_packets_as_mono() {
+        let (tx, rx) = mpsc::channel(4);
+        let mut reader = PacketReader::new(rx, 48_000, 2);
+
+        let left: Vec<i16> = sine(440.0, 48_000, 0.5, 0, 960).iter().map(|v| (v * 32768.0) as i16).collect();
+        let interleaved: Vec<i16> = left.iter().flat_map(|&sample| [sample, sample]).collect();
+        tx.send(ListenerEvent::AudioPacket(interleaved, None)).await.unwrap();
+
+        let frames = reader.read_frames(960).await.unwrap();
+        assert_eq!(frames.len(), 960);
+        for (mono, sample) in frames.iter().zip(left) {
+            assert_eq!(*mono, sample as f64 / 32768.0);
         }
+    }
+
+    #[tokio::test]
+    async fn fills_lost_packets_with_silence() {
+        let (tx, rx) = mpsc::channel(8);
+        let mut reader = PacketReader::new(rx, 16_000, 1);
+
+        // 20ms packets at 16kHz, the packet at 960 never arrives.
+        tx.send(packet(320, 16384, 1, 0)).await.unwrap();
+        tx.send(packet(320, 16384, 3, 1920)).await.unwrap();
+
+        let frames = reader.read_frames(960).await.unwrap();
+        assert!(frames[..320].iter().all(|&sample| sample == 0.5));
+        assert!(frames[320..640].iter().all(|&sample| sample == 0.0));
+        assert!(frames[640..].iter().all(|&sample| sample == 0.5));
+    }
+
+    #[tokio::test]
+    async fn counts_silent_ticks_towards_gaps() {
+        let (tx, rx) = mpsc::channel(8);
+        let mut reader = PacketReader::new(rx, 16_000, 1);
+
+        // Two ticks of silence cover 40ms of the 60ms gap, only 20ms more is missing.
+        tx.send(packet(320, 16384, 1, 0)).await.unwrap();
+        tx.send(ListenerEvent::Silence).await.unwrap();
+        tx.send(ListenerEvent::Silence).await.unwrap();
+        tx.send(packet(320, 16384, 2, 3840)).await.unwrap();
 
-        return true;
+        let frames = reader.read_frames(1600).await.unwrap();
+        assert!(frames[..320].iter().all(|&sample| sample == 0.5));
+        assert!(frames[320..1280].iter().all(|&sample| sample == 0.0));
+        assert!(frames[1280..].iter().all(|&sample| sample == 0.5));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn drops_duplicate_and_late_packets() {
+        let (tx, rx) = mpsc::channel(8);
+        let mut reader = PacketReader::new(rx, 16_000, 1);
+
+        tx.send(packet(320, 16384, 1, 0)).await.unwrap();
+        tx.send(packet(320, 16384, 1, 0)).await.unwrap();
+        tx.send(packet(320, 8192, 3, 1920)).await.unwrap();
+        tx.send(packet(320, -16384, 2, 960)).await.unwrap();
+        tx.send(packet(320, 8192, 4, 2880)).await.unwrap();
+
+        let frames = reader.read_frames(1280).await.unwrap();
+        assert!(frames[..320].iter().all(|&sample| sample == 0.5));
+        assert!(frames[320..640].iter().all(|&sample| sample == 0.0));
+        assert!(frames[640..].iter().all(|&sample| sample == 0.25));
+    }
+
+    #[tokio::test]
+    async fn stops_on_disconnect() {
+        let (tx, rx) = mpsc::channel(4);
+        let mut reader = PacketReader::new(rx, 16_000, 1);
+
+        tx.send(ListenerEvent::Silence).await.unwrap();
+        tx.send(ListenerEvent::Disconnect).await.unwrap();
+        assert!(reader.read_frames(FRAME_LENGTH).await.is_none());
+    }
+}
@@ -0,0 +1,133 @@
+// Shorter sentences aren't worth a synthesis request of their own and are
+// joined with the one that follows.
+const MIN_SENTENCE_CHARS: usize = 20;
+
+// Words whose trailing period doesn't end the sentence, as in "Mr. Smith".
+const ABBREVIATIONS: [&str; 11] = ["mr", "mrs", "ms", "dr", "prof", "st", "jr", "sr", "vs", "e.g", "i.e"];
+
+// Cuts streamed text into sentences as soon as each one is complete, so they
+// can be spoken while the rest is still being generated.
+#[derive(Default)]
+pub struct SentenceSplitter {
+    pending: String,
+}
+
+impl SentenceSplitter {
+    // Adds the next piece of text, returning any sentences it completed.
+    pub fn push(&mut self, text: &str) -> Vec<String> {
+        self.pending.push_str(text);
+
+        let mut sentences = Vec::new();
+        while let Some(end) = self.find_boundary() {
+            let sentence: String = self.pending.drain(..end).collect();
+            let sentence = sentence.trim();
+            if !sentence.is_empty() {
+                sentences.push(sentence.to_string());
+            }
+        }
+        sentences
+    }
+
+    // Whatever is left once the text has ended.
+    pub fn finish(&mut self) -> Option<String> {
+        let rest = std::mem::take(&mut self.pending);
+        let rest = rest.trim();
+        if rest.is_empty() {
+            None
+        } else {
+            Some(rest.to_string())
+        }
+    }
+
+    fn find_boundary(&self) -> Option<usize> {
+        let mut chars = self.pending.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            let end = i + c.len_utf8();
+            let is_boundary = match c {
+                '\n' => true,
+                // Only once the next character shows the sentence really
+                // ended, "3.5" or "..." mid-sentence aren't boundaries.
+                '.' | '!' | '?' => {
+                    matches!(chars.peek(), Some((_, next)) if next.is_whitespace())
+                        && !(c == '.' && self.ends_with_abbreviation(i))
+                }
+                _ => false,
+            };
+            if is_boundary && self.pending[..end].trim().chars().count() >= MIN_SENTENCE_CHARS {
+                return Some(end);
+            }
+        }
+        None
+    }
+
+    fn ends_with_abbreviation(&self, end: usize) -> bool {
+        let word = self.pending[..end]
+            .rsplit(char::is_whitespace)
+            .next()
+            .unwrap_or("")
+            .trim_start_matches(|c: char| !c.is_alphanumeric())
+            .to_lowercase();
+        ABBREVIATIONS.contains(&word.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(pieces: &[&str]) -> Vec<String> {
+        let mut splitter = SentenceSplitter::default();
+        let mut sentences: Vec<String> = pieces.iter().flat_map(|piece| splitter.push(piece)).collect();
+        sentences.extend(splitter.finish());
+        sentences
+    }
+
+    #[test]
+    fn splits_on_sentence_ends_and_newlines() {
+        assert_eq!(
+            split(&["Queued up your song for you! Anything else you want?\nJust let me know."]),
+            vec!["Queued up your song for you!", "Anything else you want?", "Just let me know."]
+        );
+    }
+
+    #[test]
+    fn joins_short_sentences_with_the_next() {
+        assert_eq!(
+            split(&["Sure. Okay. Playing that song for you now. Done."]),
+            vec!["Sure. Okay. Playing that song for you now.", "Done."]
+        );
+    }
+
+    #[test]
+    fn keeps_numbers_and_ellipses_together() {
+        assert_eq!(
+            split(&["The song is 3.5 minutes long. Hold on... let me think about that."]),
+            vec!["The song is 3.5 minutes long.", "Hold on... let me think about that."]
+        );
+    }
+
+    #[test]
+    fn keeps_abbreviations_together() {
+        assert_eq!(
+            split(&["I asked Mr. Smith and Dr. Jones about it. They said no."]),
+            vec!["I asked Mr. Smith and Dr. Jones about it.", "They said no."]
+        );
+    }
+
+    #[test]
+    fn waits_for_a_boundary_split_across_pushes() {
+        let mut splitter = SentenceSplitter::default();
+        assert!(splitter.push("This sentence is long enough.").is_empty());
+        assert_eq!(splitter.push(" And"), vec!["This sentence is long enough."]);
+        assert!(splitter.push(" then some").is_empty());
+        assert_eq!(splitter.finish(), Some("And then some".to_string()));
+        assert_eq!(splitter.finish(), None);
+    }
+
+    #[test]
+    fn finish_skips_whitespace() {
+        let mut splitter = SentenceSplitter::default();
+        assert!(splitter.push("  \n ").is_empty());
+        assert_eq!(splitter.finish(), None);
+    }
+}
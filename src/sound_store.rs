@@ -1,14 +1,227 @@
 use std::{
     collections::HashMap,
+    env,
+    error::Error,
+    path::PathBuf,
     sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
 };
 
+use bytes::Bytes;
 use songbird::{
     input::{cached::Memory, File},
     typemap::TypeMapKey,
 };
 
-pub type SoundStore = HashMap<String, Memory>;
+use crate::text_to_speech::TextToSpeech;
+use crate::tools;
+
+// Files in the sounds directory that are loaded, anything else is ignored.
+const SOUND_EXTENSIONS: [&str; 7] = ["mp3", "wav", "ogg", "flac", "opus", "m4a", "aac"];
+
+// Synthesized speech is looked up by the backend's identity, which covers
+// its voice and format, and the text.
+type SpeechKey = (String, String);
+
+struct CachedSpeech {
+    audio: Bytes,
+    // Pre-synthesized phrases are never evicted.
+    pinned: bool,
+    last_used: Instant,
+}
+
+// What a sound file looked like when it was last loaded, a reload only
+// touches files that changed since.
+#[derive(Clone, PartialEq)]
+struct SoundFile {
+    path: PathBuf,
+    modified: SystemTime,
+    len: u64,
+}
+
+// Sound effects by name, plus recently synthesized speech so lines that
+// come up again don't go back to the backend.
+pub struct SoundStore {
+    dir: PathBuf,
+    sounds: HashMap<String, Memory>,
+    files: HashMap<String, SoundFile>,
+    // Names the assistant uses for its sounds, mapped to the sounds they
+    // play, e.g. "acknowledge" to "openai_onyx_huh".
+    aliases: HashMap<String, String>,
+    speech: HashMap<SpeechKey, CachedSpeech>,
+    // How many unpinned lines are kept.
+    max_speech: usize,
+}
+
+impl SoundStore {
+    pub fn new(dir: PathBuf, aliases: HashMap<String, String>, max_speech: usize) -> Self {
+        SoundStore {
+            dir: dir,
+            sounds: HashMap::new(),
+            files: HashMap::new(),
+            aliases: aliases,
+            speech: HashMap::new(),
+            max_speech: max_speech,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Memory> {
+        let name = self.aliases.get(name).map(String::as_str).unwrap_or(name);
+        self.sounds.get(name)
+    }
+
+    fn report_missing_aliases(&self) {
+        for (alias, name) in &self.aliases {
+            if !self.sounds.contains_key(name) {
+                println!("Sound {} for {} isn't in {}", name, alias, self.dir.display());
+            }
+        }
+    }
+
+    pub fn get_speech(&mut self, identity: &str, text: &str) -> Option<Bytes> {
+        let cached = self.speech.get_mut(&(identity.to_string(), text.to_string()))?;
+        cached.last_used = Instant::now();
+        Some(cached.audio.clone())
+    }
+
+    pub fn insert_speech(&mut self, identity: &str, text: &str, audio: Bytes, pinned: bool) {
+        if !pinned && self.max_speech == 0 {
+            return;
+        }
+        self.speech.insert(
+            (identity.to_string(), text.to_string()),
+            CachedSpeech {
+                audio: audio,
+                pinned: pinned,
+                last_used: Instant::now(),
+            },
+        );
+
+        let mut unpinned = self.speech.values().filter(|cached| !cached.pinned).count();
+        while unpinned > self.max_speech {
+            let oldest = self
+                .speech
+                .iter()
+                .filter(|(_, cached)| !cached.pinned)
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(key) => {
+                    self.speech.remove(&key);
+                    unpinned -= 1;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+// Every audio file in the directory, by file stem.
+async fn scan_sounds(dir: &PathBuf) -> Result<HashMap<String, SoundFile>, Box<dyn Error + Send + Sync>> {
+    let mut paths = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let is_sound = match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) => SOUND_EXTENSIONS.contains(&extension.to_lowercase().as_str()),
+            None => false,
+        };
+        if is_sound {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut files: HashMap<String, SoundFile> = HashMap::new();
+    for path in paths {
+        let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        if let Some(file) = files.get(&name) {
+            println!("Skipped {}, {} is already called {}", path.display(), file.path.display(), name);
+            continue;
+        }
+        let metadata = tokio::fs::metadata(&path).await?;
+        files.insert(
+            name,
+            SoundFile {
+                path: path,
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                len: metadata.len(),
+            },
+        );
+    }
+    Ok(files)
+}
+
+async fn load_sound(file: &SoundFile) -> Result<Memory, Box<dyn Error + Send + Sync>> {
+    let sound = Memory::new(File::new(file.path.clone()).into()).await?;
+    let _ = sound.raw.spawn_loader();
+    Ok(sound)
+}
+
+// Brings the store in line with the sounds directory, loading new and changed
+// files and dropping removed ones. A file that can't be loaded is reported
+// and skipped until it changes again.
+pub async fn reload_sounds(sound_store: &Arc<Mutex<SoundStore>>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (dir, known) = {
+        let sound_store = sound_store.lock().unwrap();
+        (sound_store.dir.clone(), sound_store.files.clone())
+    };
+    let files = scan_sounds(&dir).await?;
+    if files == known {
+        return Ok(());
+    }
+
+    let mut loaded = HashMap::new();
+    for (name, file) in &files {
+        if known.get(name) == Some(file) {
+            continue;
+        }
+        match load_sound(file).await {
+            Ok(sound) => {
+                println!("Loaded sound {} from {}", name, file.path.display());
+                loaded.insert(name.clone(), sound);
+            }
+            Err(e) => println!("Couldn't load sound {}: {}", file.path.display(), e),
+        }
+    }
+
+    let mut sound_store = sound_store.lock().unwrap();
+    for name in known.keys() {
+        if !files.contains_key(name) {
+            println!("Removed sound {}", name);
+            sound_store.sounds.remove(name);
+        }
+    }
+    for (name, file) in &files {
+        if known.get(name) != Some(file) && !loaded.contains_key(name) {
+            // Don't keep playing a stale version of a broken file.
+            sound_store.sounds.remove(name);
+        }
+    }
+    sound_store.sounds.extend(loaded);
+    sound_store.files = files;
+    sound_store.report_missing_aliases();
+    Ok(())
+}
+
+// Checks the sounds directory every SOUNDS_POLL_SECS (default 2) seconds so
+// sounds can be added or swapped out without a restart.
+pub async fn watch_sounds(sound_store: Arc<Mutex<SoundStore>>) {
+    let poll_secs: u64 = env::var("SOUNDS_POLL_SECS")
+        .unwrap_or("2".into())
+        .parse()
+        .expect("Couldn't parse env SOUNDS_POLL_SECS!");
+    let mut interval = tokio::time::interval(Duration::from_secs(poll_secs.max(1)));
+    loop {
+        interval.tick().await;
+        if let Err(e) = reload_sounds(&sound_store).await {
+            println!("Couldn't reload sounds: {}", e);
+        }
+    }
+}
 
 pub struct SoundStoreKey {}
 
@@ -16,20 +229,54 @@ impl TypeMapKey for SoundStoreKey {
     type Value = Arc<Mutex<SoundStore>>;
 }
 
-pub async fn init_sound_store() -> HashMap<String, Memory> {
-    let mut audio_map = HashMap::new();
+// Synthesizes the phrases the assistant says all the time up front, a failed
+// one is left to be synthesized when it's needed. With TTS_CACHE_DIR set
+// they come off the disk after the first start.
+async fn presynthesize(sound_store: &mut SoundStore, text_to_speech: &Arc<dyn TextToSpeech>, phrases: &[String]) {
+    let identity = text_to_speech.identity();
+    for phrase in phrases {
+        match text_to_speech.synthesize(phrase).await {
+            Ok(speech) => sound_store.insert_speech(&identity, phrase, speech.audio, true),
+            Err(e) => println!("Couldn't pre-synthesize \"{}\": {}", phrase, e),
+        }
+    }
+}
 
-    let acknowledge_src = Memory::new(File::new("../../resources/openai_onyx_huh.mp3").into())
-        .await
-        .unwrap();
-    let _ = acknowledge_src.raw.spawn_loader();
-    audio_map.insert("acknowledge".into(), acknowledge_src);
+// SOUNDS_DIR holds the sound effects, "resources" by default, each one is
+// named after its file. ACKNOWLEDGE_SOUND and PING_SOUND pick the ones played
+// after the wake word and while waiting on a reply. TTS_PRESYNTHESIZE lists
+// phrases to synthesize at startup, separated by |, the tool replies by
+// default. TTS_MEMORY_CACHE_ENTRIES is how many other lines are kept in
+// memory, 64 by default.
+pub async fn init_sound_store(text_to_speech: &Arc<dyn TextToSpeech>) -> Arc<Mutex<SoundStore>> {
+    let dir = env::var("SOUNDS_DIR").unwrap_or("resources".into());
+    let mut aliases = HashMap::new();
+    aliases.insert(
+        "acknowledge".to_string(),
+        env::var("ACKNOWLEDGE_SOUND").unwrap_or("openai_onyx_huh".into()),
+    );
+    aliases.insert("ping".to_string(), env::var("PING_SOUND").unwrap_or("ping".into()));
+    let max_speech: usize = env::var("TTS_MEMORY_CACHE_ENTRIES")
+        .unwrap_or("64".into())
+        .parse()
+        .expect("Couldn't parse env TTS_MEMORY_CACHE_ENTRIES!");
+    let mut sound_store = SoundStore::new(PathBuf::from(dir), aliases, max_speech);
 
-    let ping_src = Memory::new(File::new("../../resources/ping.mp3").into())
-        .await
-        .unwrap();
-    let _ = ping_src.raw.spawn_loader();
-    audio_map.insert("ping".into(), ping_src);
+    let phrases: Vec<String> = match env::var("TTS_PRESYNTHESIZE") {
+        Ok(phrases) => phrases
+            .split('|')
+            .map(str::trim)
+            .filter(|phrase| !phrase.is_empty())
+            .map(String::from)
+            .collect(),
+        Err(_) => vec![tools::ACKNOWLEDGE_REPLY.into(), tools::ERROR_REPLY.into()],
+    };
+    presynthesize(&mut sound_store, text_to_speech, &phrases).await;
 
-    audio_map
+    // The assistant still works without its sounds, it just can't play them.
+    let sound_store = Arc::new(Mutex::new(sound_store));
+    if let Err(e) = reload_sounds(&sound_store).await {
+        println!("Couldn't load sounds: {}", e);
+    }
+    sound_store
 }
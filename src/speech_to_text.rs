@@ -0,0 +1,129 @@
+use std::{env, error::Error, io::Cursor, process::Stdio, sync::Arc};
+
+use async_openai::{
+    config::OpenAIConfig,
+    types::{AudioInput, CreateTranscriptionRequestArgs},
+    Client,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use simple_error::bail;
+use tokio::process::Command;
+use uuid::Uuid;
+use wav::WAV_FORMAT_PCM;
+
+pub type SpeechToTextResult = Result<String, Box<dyn Error + Send + Sync>>;
+
+#[async_trait]
+pub trait SpeechToText: Send + Sync {
+    // Transcribes a single utterance, encoded as a 16-bit PCM WAV file.
+    async fn transcribe(&self, wav: Bytes) -> SpeechToTextResult;
+}
+
+pub struct OpenAISpeechToText {
+    oai_client: Arc<Client<OpenAIConfig>>,
+    model: String,
+}
+
+impl OpenAISpeechToText {
+    pub fn new(oai_client: Arc<Client<OpenAIConfig>>, model: String) -> Self {
+        OpenAISpeechToText {
+            oai_client: oai_client,
+            model: model,
+        }
+    }
+}
+
+#[async_trait]
+impl SpeechToText for OpenAISpeechToText {
+    async fn transcribe(&self, wav: Bytes) -> SpeechToTextResult {
+        let request = CreateTranscriptionRequestArgs::default()
+            .file(AudioInput::from_bytes("utterance.wav".into(), wav))
+            .model(self.model.clone())
+            .build()?;
+
+        let response = self.oai_client.audio().transcribe(request).await?;
+        Ok(response.text)
+    }
+}
+
+// Runs a local transcription executable (e.g. whisper.cpp) against a WAV file
+// and reads the transcript from its stdout. A `{file}` argument is replaced by
+// the path of the WAV file, otherwise the path is appended as the last argument.
+pub struct CommandSpeechToText {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandSpeechToText {
+    pub fn new(program: String, args: Vec<String>) -> Self {
+        CommandSpeechToText {
+            program: program,
+            args: args,
+        }
+    }
+}
+
+#[async_trait]
+impl SpeechToText for CommandSpeechToText {
+    async fn transcribe(&self, wav: Bytes) -> SpeechToTextResult {
+        let mut path = env::temp_dir();
+        path.push(format!("{}.wav", Uuid::new_v4()));
+        tokio::fs::write(&path, &wav).await?;
+        let path_arg = path.to_string_lossy().to_string();
+
+        let mut args: Vec<String> = self
+            .args
+            .iter()
+            .map(|arg| arg.replace("{file}", &path_arg))
+            .collect();
+        if !self.args.iter().any(|arg| arg.contains("{file}")) {
+            args.push(path_arg);
+        }
+
+        let output = Command::new(&self.program)
+            .args(&args)
+            .stdin(Stdio::null())
+            .output()
+            .await;
+        let _ = tokio::fs::remove_file(&path).await;
+
+        let output = output?;
+        if !output.status.success() {
+            bail!(
+                "{} exited with {}: {}",
+                self.program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+pub fn encode_wav(samples: Vec<i16>, sample_rate: u32) -> Bytes {
+    let mut bytes = Cursor::new(vec![]);
+    let bit_depth = wav::bit_depth::BitDepth::Sixteen(samples);
+    let header = wav::Header::new(WAV_FORMAT_PCM, 1, sample_rate, 16);
+    wav::write(header, &bit_depth, &mut bytes).unwrap();
+    Bytes::from(bytes.into_inner())
+}
+
+// STT_BACKEND selects the engine: "openai" (default) or "command".
+pub fn init_speech_to_text(oai_client: Arc<Client<OpenAIConfig>>) -> Arc<dyn SpeechToText> {
+    let backend = env::var("STT_BACKEND").unwrap_or("openai".into());
+    match backend.as_str() {
+        "openai" => {
+            let model = env::var("STT_MODEL").unwrap_or("whisper-1".into());
+            Arc::new(OpenAISpeechToText::new(oai_client, model))
+        }
+        "command" => {
+            let command = env::var("STT_COMMAND").expect("Couldn't find env STT_COMMAND!");
+            let mut parts = command.split_whitespace().map(String::from);
+            let program = parts.next().expect("STT_COMMAND is empty!");
+            Arc::new(CommandSpeechToText::new(program, parts.collect()))
+        }
+        _ => panic!("Unknown STT_BACKEND {}!", backend),
+    }
+}
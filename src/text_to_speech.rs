@@ -0,0 +1,208 @@
+use std::{env, error::Error, path::PathBuf, process::Stdio, sync::Arc};
+
+use async_openai::{
+    config::OpenAIConfig,
+    types::{CreateSpeechRequestArgs, SpeechModel, SpeechResponseFormat, Voice},
+    Client,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use simple_error::bail;
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::tts_cache::DiskCachedTextToSpeech;
+
+pub type TextToSpeechResult = Result<SynthesizedSpeech, Box<dyn Error + Send + Sync>>;
+
+pub struct SynthesizedSpeech {
+    pub audio: Bytes,
+    // File extension of the encoded audio, e.g. "mp3" or "wav".
+    pub format: String,
+}
+
+#[async_trait]
+pub trait TextToSpeech: Send + Sync {
+    async fn synthesize(&self, text: &str) -> TextToSpeechResult;
+
+    // Names the backend and its settings, the same text synthesized by two
+    // backends with the same identity sounds the same.
+    fn identity(&self) -> String;
+}
+
+pub struct OpenAITextToSpeech {
+    oai_client: Arc<Client<OpenAIConfig>>,
+    model: SpeechModel,
+    voice: Voice,
+    format: SpeechResponseFormat,
+}
+
+impl OpenAITextToSpeech {
+    pub fn new(
+        oai_client: Arc<Client<OpenAIConfig>>,
+        model: SpeechModel,
+        voice: Voice,
+        format: SpeechResponseFormat,
+    ) -> Self {
+        OpenAITextToSpeech {
+            oai_client: oai_client,
+            model: model,
+            voice: voice,
+            format: format,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self.format {
+            SpeechResponseFormat::Mp3 => "mp3",
+            SpeechResponseFormat::Opus => "opus",
+            SpeechResponseFormat::Aac => "aac",
+            SpeechResponseFormat::Flac => "flac",
+        }
+    }
+}
+
+#[async_trait]
+impl TextToSpeech for OpenAITextToSpeech {
+    async fn synthesize(&self, text: &str) -> TextToSpeechResult {
+        let request = CreateSpeechRequestArgs::default()
+            .input(text)
+            .voice(self.voice.clone())
+            .model(self.model.clone())
+            .response_format(self.format.clone())
+            .build()?;
+
+        let speech = self.oai_client.audio().speech(request).await?;
+        Ok(SynthesizedSpeech {
+            audio: speech.bytes,
+            format: self.extension().into(),
+        })
+    }
+
+    fn identity(&self) -> String {
+        format!("openai:{:?}:{:?}:{}", self.model, self.voice, self.extension())
+    }
+}
+
+// Runs a local synthesis executable (e.g. espeak or piper) with the text on
+// stdin and reads the encoded audio from its stdout.
+pub struct CommandTextToSpeech {
+    program: String,
+    args: Vec<String>,
+    format: String,
+}
+
+impl CommandTextToSpeech {
+    pub fn new(program: String, args: Vec<String>, format: String) -> Self {
+        CommandTextToSpeech {
+            program: program,
+            args: args,
+            format: format,
+        }
+    }
+}
+
+#[async_trait]
+impl TextToSpeech for CommandTextToSpeech {
+    async fn synthesize(&self, text: &str) -> TextToSpeechResult {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(text.as_bytes()).await?;
+        drop(stdin);
+
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            bail!(
+                "{} exited with {}: {}",
+                self.program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        Ok(SynthesizedSpeech {
+            audio: Bytes::from(output.stdout),
+            format: self.format.clone(),
+        })
+    }
+
+    fn identity(&self) -> String {
+        format!("command:{} {}:{}", self.program, self.args.join(" "), self.format)
+    }
+}
+
+fn parse_voice(voice: &str) -> Voice {
+    match voice {
+        "alloy" => Voice::Alloy,
+        "echo" => Voice::Echo,
+        "fable" => Voice::Fable,
+        "onyx" => Voice::Onyx,
+        "nova" => Voice::Nova,
+        "shimmer" => Voice::Shimmer,
+        _ => panic!("Unknown TTS_VOICE {}!", voice),
+    }
+}
+
+fn parse_format(format: &str) -> SpeechResponseFormat {
+    match format {
+        "mp3" => SpeechResponseFormat::Mp3,
+        "opus" => SpeechResponseFormat::Opus,
+        "aac" => SpeechResponseFormat::Aac,
+        "flac" => SpeechResponseFormat::Flac,
+        _ => panic!("Unknown TTS_FORMAT {}!", format),
+    }
+}
+
+fn parse_model(model: &str) -> SpeechModel {
+    match model {
+        "tts-1" => SpeechModel::Tts1,
+        "tts-1-hd" => SpeechModel::Tts1Hd,
+        _ => SpeechModel::Other(model.into()),
+    }
+}
+
+// TTS_BACKEND selects the engine: "openai" (default) or "command".
+// TTS_CACHE_DIR keeps synthesized speech on disk, up to TTS_CACHE_MAX_MB
+// (default 100) megabytes.
+pub fn init_text_to_speech(oai_client: Arc<Client<OpenAIConfig>>) -> Arc<dyn TextToSpeech> {
+    let backend = env::var("TTS_BACKEND").unwrap_or("openai".into());
+    let text_to_speech: Arc<dyn TextToSpeech> = match backend.as_str() {
+        "openai" => {
+            let model = env::var("TTS_MODEL").unwrap_or("tts-1".into());
+            let voice = env::var("TTS_VOICE").unwrap_or("onyx".into());
+            let format = env::var("TTS_FORMAT").unwrap_or("mp3".into());
+            Arc::new(OpenAITextToSpeech::new(
+                oai_client,
+                parse_model(&model),
+                parse_voice(&voice),
+                parse_format(&format),
+            ))
+        }
+        "command" => {
+            let command = env::var("TTS_COMMAND").expect("Couldn't find env TTS_COMMAND!");
+            let mut parts = command.split_whitespace().map(String::from);
+            let program = parts.next().expect("TTS_COMMAND is empty!");
+            let format = env::var("TTS_COMMAND_FORMAT").unwrap_or("wav".into());
+            Arc::new(CommandTextToSpeech::new(program, parts.collect(), format))
+        }
+        _ => panic!("Unknown TTS_BACKEND {}!", backend),
+    };
+
+    match env::var("TTS_CACHE_DIR") {
+        Ok(dir) => {
+            let max_mb: u64 = env::var("TTS_CACHE_MAX_MB")
+                .unwrap_or("100".into())
+                .parse()
+                .expect("Couldn't parse env TTS_CACHE_MAX_MB!");
+            let cache = DiskCachedTextToSpeech::new(text_to_speech, PathBuf::from(dir), max_mb * 1024 * 1024)
+                .expect("Couldn't open TTS_CACHE_DIR!");
+            Arc::new(cache)
+        }
+        Err(_) => text_to_speech,
+    }
+}
@@ -0,0 +1,316 @@
+use async_openai::types::{ChatCompletionTool, ChatCompletionToolArgs, FunctionCall, FunctionObjectArgs};
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use serenity::model::id::GuildId;
+use tokio::sync::broadcast;
+
+use crate::actions::{AssistantAction, MusicBotAction};
+
+pub const ACKNOWLEDGE_REPLY: &str = "On it!";
+pub const ERROR_REPLY: &str = "Sorry I made a fucky wucky!";
+
+// Everything a tool may touch while executing.
+pub struct ToolContext {
+    pub guild_id: GuildId,
+    pub action_channel: broadcast::Sender<AssistantAction>,
+}
+
+pub struct ToolOutcome {
+    // Spoken back to the user if the model can't confirm the action itself,
+    // the waiting ping is stopped when there is nothing to say.
+    pub reply: Option<String>,
+    // What happened, in words the model can make sense of.
+    pub result: String,
+    // Hands the assistant's attention back so anyone can wake it again.
+    pub end_conversation: bool,
+}
+
+impl ToolOutcome {
+    pub fn done(reply: &str, result: &str) -> Self {
+        ToolOutcome {
+            reply: Some(reply.into()),
+            result: result.into(),
+            end_conversation: true,
+        }
+    }
+
+    pub fn failed(result: String) -> Self {
+        ToolOutcome {
+            reply: Some(ERROR_REPLY.into()),
+            result: result,
+            end_conversation: true,
+        }
+    }
+}
+
+#[async_trait]
+pub trait Tool: Send + Sync {
+    type Args: DeserializeOwned + Send;
+
+    fn name(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    // JSON schema of `Args`.
+    fn parameters(&self) -> Value;
+    async fn execute(&self, args: Self::Args, ctx: &ToolContext) -> ToolOutcome;
+}
+
+// Object safe view of a `Tool`, parsing the raw arguments into its typed struct.
+#[async_trait]
+trait RegisteredTool: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn schema(&self) -> ChatCompletionTool;
+    async fn call(&self, arguments: &str, ctx: &ToolContext) -> ToolOutcome;
+}
+
+#[async_trait]
+impl<T: Tool> RegisteredTool for T {
+    fn name(&self) -> &'static str {
+        Tool::name(self)
+    }
+
+    fn schema(&self) -> ChatCompletionTool {
+        ChatCompletionToolArgs::default()
+            .function(
+                FunctionObjectArgs::default()
+                    .name(Tool::name(self))
+                    .description(self.description())
+                    .parameters(self.parameters())
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap()
+    }
+
+    async fn call(&self, arguments: &str, ctx: &ToolContext) -> ToolOutcome {
+        // Models tend to send an empty string for functions without parameters.
+        let arguments = if arguments.trim().is_empty() { "{}" } else { arguments };
+        match serde_json::from_str::<T::Args>(arguments) {
+            Ok(args) => self.execute(args, ctx).await,
+            Err(e) => ToolOutcome::failed(format!(
+                "invalid arguments for {}: {}",
+                Tool::name(self),
+                e
+            )),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn RegisteredTool>>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        ToolRegistry { tools: Vec::new() }
+    }
+
+    pub fn register<T: Tool + 'static>(&mut self, tool: T) {
+        assert!(
+            self.tools.iter().all(|t| t.name() != Tool::name(&tool)),
+            "tool {} registered twice",
+            Tool::name(&tool)
+        );
+        self.tools.push(Box::new(tool));
+    }
+
+    pub fn schemas(&self) -> Vec<ChatCompletionTool> {
+        self.tools.iter().map(|tool| tool.schema()).collect()
+    }
+
+    pub async fn dispatch(&self, function_call: &FunctionCall, ctx: &ToolContext) -> ToolOutcome {
+        match self.tools.iter().find(|tool| tool.name() == function_call.name) {
+            Some(tool) => tool.call(&function_call.arguments, ctx).await,
+            None => ToolOutcome::failed(format!("unsupported function call: {}", function_call.name)),
+        }
+    }
+}
+
+fn send_action(ctx: &ToolContext, action: MusicBotAction) -> ToolOutcome {
+    let description = format!("{:?}", action);
+    match ctx.action_channel.send(AssistantAction::MusicBot(ctx.guild_id, action)) {
+        Ok(_) => ToolOutcome::done(ACKNOWLEDGE_REPLY, &format!("sent {} to the music bot", description)),
+        Err(_) => ToolOutcome::failed(format!("nobody is handling music bot actions, dropped {}", description)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NoArgs {}
+
+fn no_parameters() -> Value {
+    json!({ "type": "object", "properties": {} })
+}
+
+pub struct DoneTool;
+
+#[async_trait]
+impl Tool for DoneTool {
+    type Args = NoArgs;
+
+    fn name(&self) -> &'static str {
+        "done"
+    }
+
+    fn description(&self) -> &'static str {
+        "The user no longer wants to speak or already had their question answered."
+    }
+
+    fn parameters(&self) -> Value {
+        no_parameters()
+    }
+
+    async fn execute(&self, _args: NoArgs, _ctx: &ToolContext) -> ToolOutcome {
+        ToolOutcome {
+            reply: None,
+            result: "conversation ended".into(),
+            end_conversation: true,
+        }
+    }
+}
+
+// Music bot commands that don't take any arguments.
+pub struct MusicBotTool {
+    name: &'static str,
+    description: &'static str,
+    action: MusicBotAction,
+}
+
+#[async_trait]
+impl Tool for MusicBotTool {
+    type Args = NoArgs;
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn description(&self) -> &'static str {
+        self.description
+    }
+
+    fn parameters(&self) -> Value {
+        no_parameters()
+    }
+
+    async fn execute(&self, _args: NoArgs, ctx: &ToolContext) -> ToolOutcome {
+        send_action(ctx, self.action.clone())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RequestSongArgs {
+    title: String,
+}
+
+pub struct RequestSongTool;
+
+#[async_trait]
+impl Tool for RequestSongTool {
+    type Args = RequestSongArgs;
+
+    fn name(&self) -> &'static str {
+        "request_music_bot"
+    }
+
+    fn description(&self) -> &'static str {
+        "The user wants to add a song or video by title to the music bot queue."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "title": {
+                    "type": "string",
+                    "description": "The title of the song or video, e.g. Abba - Dancing Queen"
+                }
+            },
+            "required": ["title"]
+        })
+    }
+
+    async fn execute(&self, args: RequestSongArgs, ctx: &ToolContext) -> ToolOutcome {
+        send_action(ctx, MusicBotAction::Request(args.title))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PlayPlaylistArgs {
+    playlist: String,
+}
+
+pub struct PlayPlaylistTool;
+
+#[async_trait]
+impl Tool for PlayPlaylistTool {
+    type Args = PlayPlaylistArgs;
+
+    fn name(&self) -> &'static str {
+        "play_playlist_music_bot"
+    }
+
+    fn description(&self) -> &'static str {
+        "The user wants the music bot to play a specific playlist."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "playlist": {
+                    "type": "string",
+                    "description": "The title of the playlist."
+                }
+            },
+            "required": ["playlist"]
+        })
+    }
+
+    async fn execute(&self, args: PlayPlaylistArgs, ctx: &ToolContext) -> ToolOutcome {
+        send_action(ctx, MusicBotAction::PlayPlaylist(args.playlist))
+    }
+}
+
+pub fn default_tools() -> ToolRegistry {
+    let mut registry = ToolRegistry::new();
+    registry.register(DoneTool);
+    registry.register(MusicBotTool {
+        name: "summon_music_bot",
+        description: "The user wants to add the music bot to the discord channel.",
+        action: MusicBotAction::Summon,
+    });
+    registry.register(MusicBotTool {
+        name: "dismiss_music_bot",
+        description: "The user wants to remove the music bot from the discord channel.",
+        action: MusicBotAction::Dismiss,
+    });
+    registry.register(RequestSongTool);
+    registry.register(MusicBotTool {
+        name: "skip_music_bot",
+        description: "The user wants the music bot to skip a song.",
+        action: MusicBotAction::Skip,
+    });
+    registry.register(MusicBotTool {
+        name: "shuffle_music_bot",
+        description: "The user wants the music bot to shuffle it's queue.",
+        action: MusicBotAction::Shuffle,
+    });
+    registry.register(MusicBotTool {
+        name: "clear_music_bot",
+        description: "The user wants to clear the music bot queue.",
+        action: MusicBotAction::Clear,
+    });
+    registry.register(MusicBotTool {
+        name: "loop_music_bot",
+        description: "The user wants to loop the last song in the music bot queue.",
+        action: MusicBotAction::Loop,
+    });
+    registry.register(MusicBotTool {
+        name: "bassboost_music_bot",
+        description: "The user wants to toggle bass boost for the music bot.",
+        action: MusicBotAction::BassBoost,
+    });
+    registry.register(PlayPlaylistTool);
+    registry
+}
@@ -0,0 +1,157 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use uuid::Uuid;
+
+use crate::text_to_speech::{SynthesizedSpeech, TextToSpeech, TextToSpeechResult};
+
+struct CacheEntry {
+    path: PathBuf,
+    format: String,
+    size: u64,
+    last_used: SystemTime,
+}
+
+// Keeps synthesized speech in a directory so repeated lines skip the
+// backend, dropping the least recently used files once it grows past
+// `max_bytes`.
+pub struct DiskCachedTextToSpeech {
+    inner: Arc<dyn TextToSpeech>,
+    dir: PathBuf,
+    max_bytes: u64,
+    entries: Mutex<HashMap<Uuid, CacheEntry>>,
+}
+
+impl DiskCachedTextToSpeech {
+    pub fn new(
+        inner: Arc<dyn TextToSpeech>,
+        dir: PathBuf,
+        max_bytes: u64,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        std::fs::create_dir_all(&dir)?;
+
+        let mut entries = HashMap::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let key = match path.file_stem().and_then(|stem| stem.to_str()).map(Uuid::parse_str) {
+                Some(Ok(key)) => key,
+                _ => continue,
+            };
+            let format = match path.extension().and_then(|extension| extension.to_str()) {
+                Some(format) => format.to_string(),
+                None => continue,
+            };
+            let metadata = std::fs::metadata(&path)?;
+            entries.insert(
+                key,
+                CacheEntry {
+                    path: path,
+                    format: format,
+                    size: metadata.len(),
+                    last_used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                },
+            );
+        }
+
+        let cache = DiskCachedTextToSpeech {
+            inner: inner,
+            dir: dir,
+            max_bytes: max_bytes,
+            entries: Mutex::new(entries),
+        };
+        cache.evict();
+        Ok(cache)
+    }
+
+    fn key(&self, text: &str) -> Uuid {
+        let name = format!("{}\n{}", self.inner.identity(), text);
+        Uuid::new_v5(&Uuid::NAMESPACE_OID, name.as_bytes())
+    }
+
+    async fn load(&self, key: Uuid) -> Option<SynthesizedSpeech> {
+        let (path, format) = {
+            let mut entries = self.entries.lock().unwrap();
+            let entry = entries.get_mut(&key)?;
+            entry.last_used = SystemTime::now();
+            (entry.path.clone(), entry.format.clone())
+        };
+
+        match tokio::fs::read(&path).await {
+            Ok(audio) => {
+                // The modification time is how recency survives a restart.
+                if let Ok(file) = std::fs::File::options().write(true).open(&path) {
+                    let _ = file.set_modified(SystemTime::now());
+                }
+                Some(SynthesizedSpeech {
+                    audio: Bytes::from(audio),
+                    format: format,
+                })
+            }
+            Err(e) => {
+                println!("Couldn't read cached speech {}: {}", path.display(), e);
+                self.entries.lock().unwrap().remove(&key);
+                None
+            }
+        }
+    }
+
+    async fn store(&self, key: Uuid, speech: &SynthesizedSpeech) {
+        let path = self.dir.join(format!("{}.{}", key, speech.format));
+        if let Err(e) = tokio::fs::write(&path, &speech.audio).await {
+            println!("Couldn't cache speech in {}: {}", path.display(), e);
+            return;
+        }
+
+        self.entries.lock().unwrap().insert(
+            key,
+            CacheEntry {
+                path: path,
+                format: speech.format.clone(),
+                size: speech.audio.len() as u64,
+                last_used: SystemTime::now(),
+            },
+        );
+        self.evict();
+    }
+
+    fn evict(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        let mut total: u64 = entries.values().map(|entry| entry.size).sum();
+        while total > self.max_bytes {
+            let oldest = match entries.iter().min_by_key(|(_, entry)| entry.last_used) {
+                Some((key, _)) => *key,
+                None => break,
+            };
+            let entry = entries.remove(&oldest).unwrap();
+            if let Err(e) = std::fs::remove_file(&entry.path) {
+                println!("Couldn't evict cached speech {}: {}", entry.path.display(), e);
+            }
+            total -= entry.size;
+        }
+    }
+}
+
+#[async_trait]
+impl TextToSpeech for DiskCachedTextToSpeech {
+    async fn synthesize(&self, text: &str) -> TextToSpeechResult {
+        let key = self.key(text);
+        if let Some(speech) = self.load(key).await {
+            return Ok(speech);
+        }
+
+        let speech = self.inner.synthesize(text).await?;
+        self.store(key, &speech).await;
+        Ok(speech)
+    }
+
+    fn identity(&self) -> String {
+        self.inner.identity()
+    }
+}
@@ -0,0 +1,209 @@
+use std::env;
+
+use cobra::Cobra;
+
+pub trait VoiceActivityDetector: Send {
+    // Feeds a single frame of mono audio, returns true if it contains speech.
+    fn is_speech(&mut self, frame: &[i16]) -> bool;
+}
+
+pub struct CobraVad {
+    cobra: Cobra,
+    threshold: f32,
+}
+
+impl CobraVad {
+    pub fn new(cobra: Cobra, threshold: f32) -> Self {
+        CobraVad {
+            cobra: cobra,
+            threshold: threshold,
+        }
+    }
+}
+
+impl VoiceActivityDetector for CobraVad {
+    fn is_speech(&mut self, frame: &[i16]) -> bool {
+        match self.cobra.process(frame) {
+            Ok(voice_probability) => voice_probability >= self.threshold,
+            Err(e) => {
+                println!("Cobra error: {}", e);
+                false
+            }
+        }
+    }
+}
+
+// Classifies frames by loudness and zero-crossing rate. Speech has to be louder
+// than `threshold_db` (dBFS) while crossing zero less often than
+// `max_zero_crossing_rate`, which rules out hiss and other broadband noise.
+// A frame of speech keeps the detector voiced for `hangover_frames` more frames
+// so short gaps between words don't count as silence.
+pub struct EnergyVad {
+    threshold_db: f64,
+    max_zero_crossing_rate: f64,
+    hangover_frames: usize,
+    hangover_remaining: usize,
+}
+
+impl EnergyVad {
+    pub fn new(threshold_db: f64, max_zero_crossing_rate: f64, hangover_frames: usize) -> Self {
+        EnergyVad {
+            threshold_db: threshold_db,
+            max_zero_crossing_rate: max_zero_crossing_rate,
+            hangover_frames: hangover_frames,
+            hangover_remaining: 0,
+        }
+    }
+
+    pub fn level_db(frame: &[i16]) -> f64 {
+        if frame.is_empty() {
+            return f64::NEG_INFINITY;
+        }
+        let energy: f64 = frame
+            .iter()
+            .map(|s| {
+                let s = *s as f64 / 32768.0;
+                s * s
+            })
+            .sum();
+        let rms = (energy / frame.len() as f64).sqrt();
+        20.0 * rms.max(1e-10).log10()
+    }
+
+    pub fn zero_crossing_rate(frame: &[i16]) -> f64 {
+        if frame.len() < 2 {
+            return 0.0;
+        }
+        let crossings = frame
+            .windows(2)
+            .filter(|pair| (pair[0] >= 0) != (pair[1] >= 0))
+            .count();
+        crossings as f64 / (frame.len() - 1) as f64
+    }
+}
+
+impl VoiceActivityDetector for EnergyVad {
+    fn is_speech(&mut self, frame: &[i16]) -> bool {
+        let voiced = Self::level_db(frame) >= self.threshold_db
+            && Self::zero_crossing_rate(frame) <= self.max_zero_crossing_rate;
+
+        if voiced {
+            self.hangover_remaining = self.hangover_frames;
+            return true;
+        }
+
+        if self.hangover_remaining > 0 {
+            self.hangover_remaining -= 1;
+            return true;
+        }
+
+        false
+    }
+}
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("Couldn't parse env {}!", key)),
+        Err(_) => default,
+    }
+}
+
+fn init_cobra() -> Cobra {
+    Cobra::new(env::var("PV_KEY").expect("Couldn't get env PV_KEY!"))
+        .expect("Unable to create Cobra")
+}
+
+// VAD_ENGINE selects the detector: "cobra" or "energy". Defaults to cobra when
+// PV_KEY is set, otherwise to the energy detector.
+pub fn init_voice_activity_detector(sample_rate: u32, frame_length: usize) -> Box<dyn VoiceActivityDetector> {
+    let default_engine = if env::var("PV_KEY").is_ok() { "cobra" } else { "energy" };
+    let engine = env::var("VAD_ENGINE").unwrap_or(default_engine.into());
+    match engine.as_str() {
+        "cobra" => {
+            let cobra = init_cobra();
+            assert!(cobra.sample_rate() == sample_rate);
+            assert!(cobra.frame_length() as usize == frame_length);
+            Box::new(CobraVad::new(cobra, env_or("VAD_THRESHOLD", 0.75)))
+        }
+        "energy" => Box::new(EnergyVad::new(
+            env_or("VAD_ENERGY_THRESHOLD_DB", -40.0),
+            env_or("VAD_MAX_ZERO_CROSSING_RATE", 0.35),
+            env_or("VAD_HANGOVER_FRAMES", 8),
+        )),
+        _ => panic!("Unknown VAD_ENGINE {}!", engine),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 16_000;
+    const FRAME_LENGTH: usize = 512;
+
+    fn detector(hangover_frames: usize) -> EnergyVad {
+        EnergyVad::new(-40.0, 0.35, hangover_frames)
+    }
+
+    fn tone(frequency: f64, amplitude: f64) -> Vec<i16> {
+        (0..FRAME_LENGTH)
+            .map(|i| {
+                let sample = amplitude * (2.0 * std::f64::consts::PI * frequency * i as f64 / SAMPLE_RATE as f64).sin();
+                (sample * 32767.0) as i16
+            })
+            .collect()
+    }
+
+    // Noise that flips sign every sample, from a fixed seed so it's the same every run.
+    fn hiss(amplitude: f64) -> Vec<i16> {
+        let mut seed: u32 = 12345;
+        (0..FRAME_LENGTH)
+            .map(|i| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                let magnitude = amplitude * (0.5 + (seed >> 16) as f64 / 131_072.0);
+                let sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+                (sign * magnitude * 32767.0) as i16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rejects_silence() {
+        let mut vad = detector(0);
+        assert!(!vad.is_speech(&vec![0; FRAME_LENGTH]));
+    }
+
+    #[test]
+    fn rejects_quiet_hiss() {
+        let frame = hiss(0.05);
+        // Loud enough to pass on energy alone, it's the zero crossings that rule it out.
+        assert!(EnergyVad::level_db(&frame) > -40.0);
+        assert!(EnergyVad::zero_crossing_rate(&frame) > 0.35);
+
+        let mut vad = detector(0);
+        assert!(!vad.is_speech(&frame));
+    }
+
+    #[test]
+    fn accepts_loud_low_tone() {
+        let mut vad = detector(0);
+        assert!(vad.is_speech(&tone(200.0, 0.5)));
+    }
+
+    #[test]
+    fn hangover_lasts_exactly_hangover_frames() {
+        let silence = vec![0; FRAME_LENGTH];
+        let mut vad = detector(3);
+        assert!(vad.is_speech(&tone(200.0, 0.5)));
+        for _ in 0..3 {
+            assert!(vad.is_speech(&silence));
+        }
+        assert!(!vad.is_speech(&silence));
+
+        // Speech starts the count over.
+        assert!(vad.is_speech(&tone(200.0, 0.5)));
+        assert!(vad.is_speech(&silence));
+    }
+}
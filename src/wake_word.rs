@@ -0,0 +1,208 @@
+use std::{env, sync::Arc};
+
+use porcupine::{Porcupine, PorcupineBuilder};
+use tokio::{runtime::Handle, sync::oneshot};
+
+use crate::{
+    speech_to_text::{encode_wav, SpeechToText},
+    vad::{self, VoiceActivityDetector},
+};
+
+pub trait WakeWordDetector: Send {
+    // Feeds a single frame of mono audio, returns true when the wake word was heard.
+    fn process(&mut self, frame: &[i16]) -> bool;
+
+    // Drops any partially heard audio, called whenever detection resumes.
+    fn reset(&mut self) {}
+}
+
+pub struct PorcupineWakeWord {
+    porcupine: Porcupine,
+}
+
+impl PorcupineWakeWord {
+    pub fn new(porcupine: Porcupine) -> Self {
+        PorcupineWakeWord { porcupine: porcupine }
+    }
+}
+
+impl WakeWordDetector for PorcupineWakeWord {
+    fn process(&mut self, frame: &[i16]) -> bool {
+        match self.porcupine.process(frame) {
+            Ok(keyword_index) => keyword_index >= 0,
+            Err(e) => {
+                println!("Porcupine error: {}", e);
+                false
+            }
+        }
+    }
+}
+
+// Trailing silence that closes a segment, in frames.
+const TRANSCRIPT_SEGMENT_END_FRAMES: usize = 15;
+// Segments with less speech than this are ignored, in frames.
+const TRANSCRIPT_MIN_VOICED_FRAMES: usize = 6;
+// Segments are cut off at this length so the wake word can't get lost in a long sentence, in frames.
+const TRANSCRIPT_MAX_SEGMENT_FRAMES: usize = 80;
+
+// Listens for the bot's name by transcribing short bursts of speech with the
+// configured speech to text backend. Slower than Porcupine but needs no key.
+pub struct TranscriptWakeWord {
+    stt: Arc<dyn SpeechToText>,
+    vad: Box<dyn VoiceActivityDetector>,
+    runtime: Handle,
+    bot_name: Vec<String>,
+    sample_rate: u32,
+    segment: Vec<i16>,
+    segment_frames: usize,
+    voiced_frames: usize,
+    silent_frames: usize,
+    pending: Option<oneshot::Receiver<String>>,
+}
+
+impl TranscriptWakeWord {
+    pub fn new(
+        stt: Arc<dyn SpeechToText>,
+        vad: Box<dyn VoiceActivityDetector>,
+        bot_name: &str,
+        sample_rate: u32,
+    ) -> Self {
+        TranscriptWakeWord {
+            stt: stt,
+            vad: vad,
+            runtime: Handle::current(),
+            bot_name: normalize_words(bot_name),
+            sample_rate: sample_rate,
+            segment: Vec::default(),
+            segment_frames: 0,
+            voiced_frames: 0,
+            silent_frames: 0,
+            pending: None,
+        }
+    }
+
+    fn clear_segment(&mut self) {
+        self.segment.clear();
+        self.segment_frames = 0;
+        self.voiced_frames = 0;
+        self.silent_frames = 0;
+    }
+
+    fn transcribe_segment(&mut self) {
+        let samples = std::mem::take(&mut self.segment);
+        let enough_speech = self.voiced_frames >= TRANSCRIPT_MIN_VOICED_FRAMES;
+        self.clear_segment();
+
+        // Only one transcription in flight at a time, segments heard meanwhile are dropped.
+        if !enough_speech || self.pending.is_some() {
+            return;
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let stt = self.stt.clone();
+        let wav = encode_wav(samples, self.sample_rate);
+        self.runtime.spawn(async move {
+            match stt.transcribe(wav).await {
+                Ok(text) => {
+                    let _ = tx.send(text);
+                }
+                Err(e) => {
+                    println!("Wake word transcription error: {}", e);
+                }
+            }
+        });
+        self.pending = Some(rx);
+    }
+
+    fn poll_pending(&mut self) -> bool {
+        let result = match self.pending.as_mut() {
+            Some(rx) => rx.try_recv(),
+            None => return false,
+        };
+
+        match result {
+            Ok(text) => {
+                self.pending = None;
+                println!("wake word stt: {}", text);
+                contains_words(&normalize_words(&text), &self.bot_name)
+            }
+            Err(oneshot::error::TryRecvError::Empty) => false,
+            Err(oneshot::error::TryRecvError::Closed) => {
+                self.pending = None;
+                false
+            }
+        }
+    }
+}
+
+impl WakeWordDetector for TranscriptWakeWord {
+    fn process(&mut self, frame: &[i16]) -> bool {
+        if self.vad.is_speech(frame) {
+            self.voiced_frames += 1;
+            self.silent_frames = 0;
+        } else {
+            self.silent_frames += 1;
+        }
+
+        if self.voiced_frames > 0 {
+            self.segment.extend_from_slice(frame);
+            self.segment_frames += 1;
+
+            if self.silent_frames >= TRANSCRIPT_SEGMENT_END_FRAMES
+                || self.segment_frames >= TRANSCRIPT_MAX_SEGMENT_FRAMES
+            {
+                self.transcribe_segment();
+            }
+        }
+
+        self.poll_pending()
+    }
+
+    fn reset(&mut self) {
+        self.clear_segment();
+        self.pending = None;
+    }
+}
+
+fn normalize_words(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn contains_words(haystack: &[String], needle: &[String]) -> bool {
+    !needle.is_empty() && haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+fn init_porcupine() -> Porcupine {
+    let mut ppn_path = std::env::current_dir().expect("Couldn't get CWD!");
+    ppn_path.push("resources");
+    ppn_path.push("hey-bozo_en_windows_v2_2_0.ppn");
+
+    PorcupineBuilder::new_with_keyword_paths(env::var("PV_KEY").expect("Couldn't get env PV_KEY!"), &[ppn_path])
+        .init()
+        .expect("Couldn't init porcupine!")
+}
+
+// WAKE_WORD_ENGINE selects the detector: "porcupine" or "transcript". Defaults
+// to porcupine when PV_KEY is set, otherwise to matching BOT_NAME in transcripts.
+pub fn init_wake_word_detector(stt: Arc<dyn SpeechToText>, sample_rate: u32, frame_length: usize) -> Box<dyn WakeWordDetector> {
+    let default_engine = if env::var("PV_KEY").is_ok() { "porcupine" } else { "transcript" };
+    let engine = env::var("WAKE_WORD_ENGINE").unwrap_or(default_engine.into());
+    match engine.as_str() {
+        "porcupine" => {
+            let porcupine = init_porcupine();
+            assert!(porcupine.sample_rate() == sample_rate);
+            assert!(porcupine.frame_length() as usize == frame_length);
+            Box::new(PorcupineWakeWord::new(porcupine))
+        }
+        "transcript" => {
+            let bot_name = env::var("BOT_NAME").unwrap_or("bozo".into());
+            let vad = vad::init_voice_activity_detector(sample_rate, frame_length);
+            Box::new(TranscriptWakeWord::new(stt, vad, &bot_name, sample_rate))
+        }
+        _ => panic!("Unknown WAKE_WORD_ENGINE {}!", engine),
+    }
+}